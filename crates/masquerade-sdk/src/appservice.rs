@@ -9,36 +9,63 @@ use axum::{Json, Router};
 use axum_extra::TypedHeader;
 use axum_extra::headers::Authorization;
 use axum_extra::headers::authorization::Bearer;
-use matrix_sdk::ruma::events::AnySyncTimelineEvent;
+use bytes::Bytes;
+use matrix_sdk::ruma::events::room::MediaSource;
 use matrix_sdk::ruma::events::room::encrypted::OriginalSyncRoomEncryptedEvent;
 use matrix_sdk::ruma::events::room::encryption::StrippedRoomEncryptionEvent;
-use matrix_sdk::ruma::events::room::member::{MembershipChange, StrippedRoomMemberEvent};
+use matrix_sdk::ruma::events::room::member::{RoomMemberEventContent, SyncRoomMemberEvent};
+use matrix_sdk::ruma::events::room::server_acl::RoomServerAclEventContent;
+use matrix_sdk::ruma::events::room::tombstone::OriginalSyncRoomTombstoneEvent;
+use matrix_sdk::ruma::events::{AnySyncTimelineEvent, RoomAccountDataEventContent, StaticEventContent, SyncStateEvent};
 use matrix_sdk::ruma::serde::Raw;
-use matrix_sdk::ruma::{OwnedTransactionId, OwnedUserId, RoomId, UserId};
+use matrix_sdk::ruma::{
+    MxcUri,
+    OwnedEventId,
+    OwnedMxcUri,
+    OwnedRoomId,
+    OwnedTransactionId,
+    OwnedUserId,
+    RoomAliasId,
+    RoomId,
+    RoomOrAliasId,
+};
 use reqwest::StatusCode;
 use serde::de::DeserializeOwned;
+use tokio::sync::OnceCell;
 
 mod builder;
+mod capabilities;
 mod device;
 mod encryption;
 mod error;
 mod event_handler;
 mod handler;
 mod http_client;
+mod keyed_lock;
+mod member;
+mod message_builder;
+mod messages;
+mod outgoing_queue;
 mod room;
+mod room_database;
 mod transaction;
 pub mod types;
 mod user;
 
 pub use self::builder::ApplicationServiceBuilder;
+pub use self::capabilities::{Feature, ServerCapabilities};
 pub use self::device::Device;
 pub use self::error::{Error, Result};
-pub use self::event_handler::EventContext;
-pub use self::room::{Direction, Room};
+pub use self::event_handler::{AccountDataContext, EventContext};
+pub use self::member::{MemberChange, MemberChangeKind, RoomMember};
+pub use self::message_builder::MessageBuilder;
+pub use self::messages::{HistoryEvent, MessagesPage, MessagesRequest, RoomEventContext, UnableToDecryptEvent};
+pub use self::room::{Direction, Room, RoomUpgrade};
 pub use self::types::*;
 pub use self::user::User;
 use crate::appservice::event_handler::EventHandlerStore;
 use crate::appservice::http_client::Client;
+use crate::appservice::outgoing_queue::OutgoingQueue;
 use crate::appservice::room::RoomStore;
 use crate::appservice::transaction::TransactionLog;
 use crate::appservice::user::UserStore;
@@ -51,6 +78,8 @@ pub struct ApplicationServiceInner {
     user_store: UserStore,
     handler_store: EventHandlerStore,
     transaction_log: TransactionLog,
+    capabilities: OnceCell<ServerCapabilities>,
+    outgoing_queue: OutgoingQueue,
 }
 
 #[derive(Clone)]
@@ -171,6 +200,14 @@ impl<S> ApplicationService<S> {
         &self.inner.config()
     }
 
+    pub fn capabilities(&self) -> Option<&ServerCapabilities> {
+        self.inner.capabilities()
+    }
+
+    pub fn supports(&self, feature: Feature) -> bool {
+        self.inner.supports(feature)
+    }
+
     pub fn client(&self) -> Arc<Client> {
         Arc::clone(&self.inner.client)
     }
@@ -183,20 +220,66 @@ impl<S> ApplicationService<S> {
         self.inner.get_user(mxid).await
     }
 
-    pub async fn get_room(&self, room_id: &RoomId) -> Option<Arc<Room>> {
-        self.inner.get_room(room_id).await
+    pub async fn get_room<'a>(&self, room: impl Into<&'a RoomOrAliasId>) -> Result<Option<Arc<Room>>> {
+        self.inner.get_room(room).await
+    }
+
+    pub async fn resolve_alias(&self, alias: &RoomAliasId) -> Result<OwnedRoomId> {
+        self.inner.resolve_alias(alias).await
+    }
+
+    pub async fn create_alias(&self, alias: &RoomAliasId, room_id: &RoomId) -> Result<()> {
+        self.inner.create_alias(alias, room_id).await
+    }
+
+    pub async fn delete_alias(&self, alias: &RoomAliasId) -> Result<()> {
+        self.inner.delete_alias(alias).await
+    }
+
+    pub async fn upload_media(
+        &self,
+        bytes: impl Into<Bytes>,
+        content_type: &str,
+        filename: Option<&str>,
+    ) -> Result<OwnedMxcUri> {
+        self.inner.upload_media(bytes, content_type, filename).await
+    }
+
+    pub async fn download_media(&self, mxc: &MxcUri) -> Result<Bytes> {
+        self.inner.download_media(mxc).await
+    }
+
+    pub async fn download_attachment(&self, source: &MediaSource) -> Result<Bytes> {
+        self.inner.download_attachment(source).await
+    }
+
+    pub async fn ensure_puppet(&self, localpart: &str, profile: Profile) -> Result<Arc<User>> {
+        self.inner.ensure_puppet(localpart, profile).await
+    }
+
+    pub async fn carry_room_account_data<C>(&self)
+    where
+        C: RoomAccountDataEventContent + StaticEventContent,
+    {
+        self.inner.room_store().carry_account_data(C::TYPE).await
+    }
+
+    pub async fn update_server_acls<F>(
+        &self,
+        sender: &User,
+        rooms: &[OwnedRoomId],
+        update: F,
+    ) -> Vec<(OwnedRoomId, Result<OwnedEventId>)>
+    where
+        F: Fn(&mut RoomServerAclEventContent),
+    {
+        self.inner.update_server_acls(sender, rooms, update).await
     }
 
     pub fn generate_registration(&self) -> Result<String> {
         let mut appservice_url = self.config().appservice.url.clone();
         appservice_url.set_port(Some(self.config().appservice.port))?;
 
-        let mxid = UserId::parse(format!(
-            "@{}:{}",
-            &self.config().appservice.username,
-            &self.config().homeserver.server_name
-        ))?;
-
         let registration = Registration {
             id: self.config().appservice.id.clone(),
             url: appservice_url,
@@ -206,7 +289,12 @@ impl<S> ApplicationService<S> {
             rate_limited: Some(false),
             protocols: None,
             namespaces: Namespaces {
-                users: vec![NamespaceEntry { exclusive: true, regex: format!("^{}$", regex::escape(mxid.as_str())) }],
+                users: self
+                    .inner
+                    .user_namespaces()
+                    .into_iter()
+                    .map(|regex| NamespaceEntry { exclusive: true, regex })
+                    .collect(),
                 aliases: vec![],
                 rooms: vec![],
             },
@@ -235,21 +323,49 @@ impl<S> ApplicationService<S> {
         self.inner.ping().await
     }
 
-    async fn on_stripped_room_member(
-        event: StrippedRoomMemberEvent,
+    async fn on_room_member(
+        event: SyncRoomMemberEvent,
         appservice: ApplicationService<S>,
         context: EventContext,
     ) -> Result<()> {
-        match event.membership_change(None) {
-            MembershipChange::Joined => {
-                appservice.inner.room_store().add_room_member(&context.room_id, event.state_key).await?;
-            }
-            MembershipChange::Left => {
-                appservice.inner.room_store().remove_room_member(&context.room_id, &event.state_key).await?;
-            }
-            _ => (),
+        let (current, prev_content, reason, is_direct) = match &event {
+            SyncStateEvent::Original(event) => (
+                RoomMember::from(&event.content),
+                event.prev_content().map(RoomMember::from),
+                event.content.reason.clone(),
+                event.content.is_direct == Some(true),
+            ),
+            SyncStateEvent::Redacted(event) => (RoomMember::from(&event.content), None, None, false),
+        };
+
+        let previous = appservice
+            .inner
+            .room_store()
+            .update_member(&context.room_id, event.state_key(), current.clone())
+            .await?
+            .or(prev_content);
+
+        let current_content = current.to_content();
+        let previous_content = previous.as_ref().map(RoomMember::to_content);
+        let change = current_content.membership_change(
+            previous_content.as_ref().map(RoomMemberEventContent::details),
+            event.sender(),
+            event.state_key(),
+        );
+        let Some(kind) = MemberChangeKind::from_change(&change) else {
+            return Ok(());
         };
 
+        if kind == MemberChangeKind::Invited
+            && is_direct
+            && let Some(user) = appservice.inner.user_store().get(event.state_key()).await
+        {
+            user.mark_direct(&context.room_id, event.sender()).await?;
+        }
+
+        let change = MemberChange { user_id: event.state_key().to_owned(), kind, previous, current, reason };
+        appservice.inner.handler_store().dispatch_member_change(change, context).await;
+
         Ok(())
     }
 
@@ -263,24 +379,35 @@ impl<S> ApplicationService<S> {
         Ok(())
     }
 
+    async fn on_room_tombstone(
+        event: OriginalSyncRoomTombstoneEvent,
+        appservice: ApplicationService<S>,
+        context: EventContext,
+    ) -> Result<()> {
+        let successor = event.content.replacement_room;
+        let via = vec![event.sender.server_name().to_owned()];
+        appservice.inner.follow_room_upgrade(&context.room_id, &successor, &via).await?;
+
+        let upgrade = RoomUpgrade { predecessor: context.room_id.clone(), successor, body: event.content.body };
+        appservice.inner.handler_store().dispatch_room_upgrade(upgrade, context).await;
+
+        Ok(())
+    }
+
     async fn on_encrypted_message(
         event: Raw<OriginalSyncRoomEncryptedEvent>,
         appservice: ApplicationService<S>,
         context: EventContext,
     ) -> Result<()> {
-        let room = appservice.get_room(&context.room_id).await.ok_or(Error::RoomNotFound(context.room_id.clone()))?;
-
-        let users = room.get_appservice_users().await?;
-        for user in users {
-            if let Some(device) = user.get_device().await {
-                let decrypted = device.encryption().decrypt_event(event.clone().cast(), &context.room_id).await;
-                if let Ok(decrypted) = decrypted {
-                    appservice.dispatch_event(decrypted.event.cast()).await?;
-                    return Ok(());
-                }
-            }
+        let room = appservice.get_room(&*context.room_id).await?.ok_or(Error::RoomNotFound(context.room_id.clone()))?;
+
+        match room.decrypt_event(event.cast()).await? {
+            HistoryEvent::Event(decrypted) => appservice.dispatch_event(decrypted).await,
+            HistoryEvent::UnableToDecrypt(utd) => Err(Error::DecryptEvent(format!(
+                "Unable to decrypt event in room {} (session {})",
+                context.room_id,
+                utd.session_id.as_deref().unwrap_or("unknown")
+            ))),
         }
-
-        Err(Error::DecryptEvent(format!("Unable to decrypt event in room {}", context.room_id)))
     }
 }
@@ -0,0 +1,153 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::VersionsResponse;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Feature {
+    EphemeralEvents,
+    DeviceMasquerading,
+    DeviceManagement,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 3] = [Feature::EphemeralEvents, Feature::DeviceMasquerading, Feature::DeviceManagement];
+
+    pub fn msc(&self) -> &'static str {
+        match self {
+            Feature::EphemeralEvents => "MSC2409",
+            Feature::DeviceMasquerading => "MSC3202",
+            Feature::DeviceManagement => "MSC4190",
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Feature::EphemeralEvents => "ephemeral and to-device events in appservice transactions",
+            Feature::DeviceMasquerading => "device masquerading and encryption extensions for appservices",
+            Feature::DeviceManagement => "device management for appservice users",
+        }
+    }
+
+    pub fn hint(&self) -> &'static str {
+        match self {
+            Feature::EphemeralEvents => {
+                "typing notifications, receipts and to-device messages will not be delivered; enable MSC2409 on the \
+                 homeserver and set `de.sorunome.msc2409.push_ephemeral: true` in the registration"
+            }
+            Feature::DeviceMasquerading => {
+                "encrypted rooms cannot be used; enable MSC3202 on the homeserver and set `org.matrix.msc3202: true` \
+                 in the registration"
+            }
+            Feature::DeviceManagement => {
+                "devices will be created by logging in instead; enable MSC4190 on the homeserver and set \
+                 `io.element.msc4190: true` in the registration"
+            }
+        }
+    }
+
+    fn stable_version(&self) -> Option<(u32, u32)> {
+        match self {
+            Feature::EphemeralEvents => Some((1, 13)),
+            Feature::DeviceMasquerading => None,
+            Feature::DeviceManagement => Some((1, 17)),
+        }
+    }
+
+    fn unstable_flags(&self) -> &'static [&'static str] {
+        match self {
+            Feature::EphemeralEvents => &["org.matrix.msc2409", "de.sorunome.msc2409"],
+            Feature::DeviceMasquerading => &["org.matrix.msc3202"],
+            Feature::DeviceManagement => &["io.element.msc4190", "org.matrix.msc4190"],
+        }
+    }
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.msc(), self.description())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ServerCapabilities {
+    pub versions: Vec<String>,
+    pub unstable_features: BTreeMap<String, bool>,
+}
+
+impl ServerCapabilities {
+    pub fn supports(&self, feature: Feature) -> bool {
+        let is_stable = feature.stable_version().is_some_and(|stable| {
+            self.versions.iter().filter_map(|version| parse_version(version)).any(|version| version >= stable)
+        });
+
+        is_stable
+            || feature.unstable_flags().iter().any(|flag| {
+                let stable_flag = format!("{}.stable", flag);
+                [*flag, stable_flag.as_str()]
+                    .iter()
+                    .any(|flag| self.unstable_features.get(*flag).copied().unwrap_or(false))
+            })
+    }
+
+    pub fn missing(&self) -> Vec<Feature> {
+        Feature::ALL.into_iter().filter(|feature| !self.supports(*feature)).collect()
+    }
+}
+
+fn parse_version(version: &str) -> Option<(u32, u32)> {
+    let (major, minor) = version.strip_prefix('v')?.split_once('.')?;
+    Some((major.parse().ok()?, minor.parse().ok()?))
+}
+
+impl From<VersionsResponse> for ServerCapabilities {
+    fn from(response: VersionsResponse) -> Self {
+        Self { versions: response.versions, unstable_features: response.unstable_features }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(versions: &[&str], flags: &[(&str, bool)]) -> ServerCapabilities {
+        ServerCapabilities {
+            versions: versions.iter().map(|version| version.to_string()).collect(),
+            unstable_features: flags.iter().map(|(flag, enabled)| (flag.to_string(), *enabled)).collect(),
+        }
+    }
+
+    #[test]
+    fn supports_enabled_unstable_flags() {
+        let capabilities = server(&["v1.1"], &[("org.matrix.msc3202", true), ("io.element.msc4190", false)]);
+        assert!(capabilities.supports(Feature::DeviceMasquerading));
+        assert!(!capabilities.supports(Feature::DeviceManagement));
+        assert!(!capabilities.supports(Feature::EphemeralEvents));
+        assert_eq!(capabilities.missing(), vec![Feature::EphemeralEvents, Feature::DeviceManagement]);
+    }
+
+    #[test]
+    fn supports_stable_flags() {
+        let capabilities = server(&[], &[("de.sorunome.msc2409.stable", true)]);
+        assert!(capabilities.supports(Feature::EphemeralEvents));
+    }
+
+    #[test]
+    fn supports_stabilized_spec_versions() {
+        let capabilities = server(&["v1.12", "v1.13"], &[]);
+        assert!(capabilities.supports(Feature::EphemeralEvents));
+        assert!(!capabilities.supports(Feature::DeviceManagement));
+        assert!(!capabilities.supports(Feature::DeviceMasquerading));
+
+        let capabilities = server(&["r0.6.1", "v1.17"], &[]);
+        assert!(capabilities.supports(Feature::DeviceManagement));
+        assert_eq!(capabilities.missing(), vec![Feature::DeviceMasquerading]);
+    }
+
+    #[test]
+    fn parses_spec_versions() {
+        assert_eq!(parse_version("v1.13"), Some((1, 13)));
+        assert_eq!(parse_version("r0.6.1"), None);
+        assert_eq!(parse_version("v1"), None);
+    }
+}
@@ -1,26 +1,51 @@
 use std::sync::{Arc, Weak};
 use std::time::Duration;
 
+use bytes::Bytes;
+use matrix_sdk::attachment::{AttachmentInfo, Thumbnail};
 use matrix_sdk::crypto::types::events::room::encrypted::EncryptedEvent;
 use matrix_sdk::deserialized_responses::DecryptedRoomEvent;
-use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
-use matrix_sdk::ruma::exports::serde_json::json;
+use matrix_sdk::ruma::events::MessageLikeEventContent;
+use matrix_sdk::ruma::events::reaction::ReactionEventContent;
+use matrix_sdk::ruma::events::relation::{Annotation, InReplyTo, Thread};
+use matrix_sdk::ruma::events::room::message::{
+    AddMentions,
+    AudioInfo,
+    AudioMessageEventContent,
+    FileInfo,
+    FileMessageEventContent,
+    ForwardThread,
+    ImageMessageEventContent,
+    MessageType,
+    Relation,
+    ReplacementMetadata,
+    RoomMessageEventContent,
+    RoomMessageEventContentWithoutRelation,
+    UnstableAudioDetailsContentBlock,
+    UnstableVoiceContentBlock,
+    VideoInfo,
+    VideoMessageEventContent,
+};
+use matrix_sdk::ruma::events::room::{EncryptedFileInit, ImageInfo, MediaSource, ThumbnailInfo};
+use matrix_sdk::ruma::exports::serde_json::{Value, json};
 use matrix_sdk::ruma::presence::PresenceState;
 use matrix_sdk::ruma::serde::Raw;
-use matrix_sdk::ruma::{DeviceId, EventId, OwnedDeviceId, OwnedEventId, RoomId, TransactionId};
+use matrix_sdk::ruma::{DeviceId, EventId, OwnedDeviceId, OwnedEventId, RoomOrAliasId, UInt, UserId, assign};
+use reqwest::StatusCode;
 use tokio::sync::Mutex;
 use tokio::sync::mpsc::{Receiver, Sender};
 use tokio_util::sync::CancellationToken;
 
 use crate::appservice::ApplicationServiceInner;
+use crate::appservice::capabilities::Feature;
 use crate::appservice::encryption::{Encryption, EncryptionInner, OwnedEncryptionSyncChanges};
 use crate::appservice::error::Error;
 use crate::appservice::handler::ApplicationServiceReference;
-use crate::appservice::http_client::parse_response;
+use crate::appservice::http_client::{discard_response, encode_path_segment, parse_response};
 use crate::appservice::room::RoomKind;
 use crate::appservice::types::CreateDeviceRequest;
 use crate::appservice::user::User;
-use crate::{Empty, Result, SendResponse};
+use crate::{Empty, Result};
 
 #[derive(Debug)]
 pub struct DeviceInner {
@@ -34,10 +59,9 @@ pub struct DeviceInner {
 impl DeviceInner {
     pub async fn new(user: &User, device_id: Option<&str>) -> Result<Arc<DeviceInner>> {
         let device_id = match device_id {
-            Some(device_id) => device_id,
-            None => &uuid::Uuid::new_v5(&uuid::Uuid::NAMESPACE_DNS, user.id().as_bytes()).to_string(),
+            Some(device_id) => OwnedDeviceId::from(device_id),
+            None => Self::default_id(user.id()),
         };
-        let device_id = OwnedDeviceId::from(device_id);
 
         let (sender, receiver) = tokio::sync::mpsc::channel(50);
         let inner = Arc::new(Self {
@@ -51,6 +75,14 @@ impl DeviceInner {
         Ok(inner)
     }
 
+    pub(crate) fn default_id(mxid: &UserId) -> OwnedDeviceId {
+        OwnedDeviceId::from(uuid::Uuid::new_v5(&uuid::Uuid::NAMESPACE_DNS, mxid.as_bytes()).to_string())
+    }
+
+    pub(crate) fn id(&self) -> &DeviceId {
+        &self.device_id
+    }
+
     pub(crate) fn upgrade(self: &Arc<Self>, user: &Arc<User>) -> Arc<Device> {
         Arc::new(Device { user: Arc::downgrade(user), inner: Arc::clone(self) })
     }
@@ -95,6 +127,17 @@ impl Device {
         let user = self.user()?;
         tracing::info!("Registering device {} for user {}", &self.id(), user.id());
 
+        if !self.appservice()?.supports(Feature::DeviceManagement) {
+            let body = json!({
+                "type": "m.login.application_service",
+                "identifier": { "type": "m.id.user", "user": user.id() },
+                "device_id": self.id(),
+                "initial_device_display_name": displayname,
+            });
+            let response = self.client()?.post("/_matrix/client/v3/login").json(&body).send().await?;
+            return parse_response(response).await;
+        }
+
         let url = format!("/_matrix/client/v3/devices/{}", self.id());
         let body = CreateDeviceRequest { display_name: displayname };
         let response = self.client()?.put(&url).query(&[("user_id", user.id())]).json(&body).send().await?;
@@ -102,6 +145,19 @@ impl Device {
         parse_response(response).await
     }
 
+    pub async fn delete(self: &Arc<Self>) -> Result<()> {
+        let user = self.user()?;
+        tracing::info!("Deleting device {} of user {}", self.id(), user.id());
+
+        let url = format!("/_matrix/client/v3/devices/{}", encode_path_segment(self.id().as_str()));
+        let response = self.client()?.delete(&url).query(&[("user_id", user.id())]).send().await?;
+        discard_response(response).await?;
+
+        self.stop().await?;
+        user.remove_device(self.id()).await;
+        EncryptionInner::remove_store(&self.appservice()?.config().database.path, self.id()).await
+    }
+
     pub(crate) async fn send_sync_changes(&self, changes: OwnedEncryptionSyncChanges) -> Result<()> {
         Ok(self.inner.sender.send(changes).await?)
     }
@@ -183,16 +239,18 @@ impl Device {
         Ok(())
     }
 
-    pub async fn decrypt_event(
+    pub async fn decrypt_event<'a>(
         self: &Arc<Self>,
         event: Raw<EncryptedEvent>,
-        room_id: &RoomId,
+        room: impl Into<&'a RoomOrAliasId>,
     ) -> Result<DecryptedRoomEvent> {
-        Ok(self.encryption().decrypt_event(event, room_id).await?)
+        let room_id = self.appservice()?.resolve_room_id(room.into()).await?;
+        self.encryption().decrypt_event(event, &room_id).await
     }
 
-    pub async fn send_receipt(&self, room_id: &RoomId, event_id: &EventId) -> Result<Empty> {
+    pub async fn send_receipt<'a>(&self, room: impl Into<&'a RoomOrAliasId>, event_id: &EventId) -> Result<Empty> {
         let user = self.user()?;
+        let room_id = self.appservice()?.resolve_room_id(room.into()).await?;
         let url = format!("/_matrix/client/v3/rooms/{}/receipt/m.read/{}", room_id, event_id);
 
         let response = self.client()?.post(url).json(&json!({})).query(&[("user_id", user.id())]).send().await?;
@@ -200,8 +258,9 @@ impl Device {
         parse_response(response).await
     }
 
-    pub async fn send_typing(&self, room_id: &RoomId, is_typing: bool) -> Result<Empty> {
+    pub async fn send_typing<'a>(&self, room: impl Into<&'a RoomOrAliasId>, is_typing: bool) -> Result<Empty> {
         let user = self.user()?;
+        let room_id = self.appservice()?.resolve_room_id(room.into()).await?;
         let url = format!("/_matrix/client/v3/rooms/{}/typing/{}", room_id, user.id());
 
         let body = json!({
@@ -214,32 +273,231 @@ impl Device {
         parse_response(response).await
     }
 
-    pub async fn send_message(
+    pub async fn send_message<'a>(
         self: &Arc<Self>,
-        room_id: &RoomId,
+        room: impl Into<&'a RoomOrAliasId>,
         content: RoomMessageEventContent,
     ) -> Result<OwnedEventId> {
-        let appservice = self.user()?.appservice()?;
-        let room = match appservice.get_room(room_id).await {
-            Some(room) => room.kind(),
-            None => return Err(Error::RoomNotFound(room_id.to_owned())),
+        self.send(room, content).await
+    }
+
+    pub async fn send_attachment<'a>(
+        self: &Arc<Self>,
+        room: impl Into<&'a RoomOrAliasId>,
+        filename: &str,
+        data: impl Into<Bytes>,
+        content_type: &str,
+        info: AttachmentInfo,
+        thumbnail: Option<Thumbnail>,
+    ) -> Result<OwnedEventId> {
+        let appservice = self.appservice()?;
+        let room_id = appservice.resolve_room_id(room.into()).await?;
+        let room = appservice.get_room(&*room_id).await?.ok_or(Error::RoomNotFound(room_id.clone()))?;
+
+        if thumbnail.is_some() && matches!(info, AttachmentInfo::Audio(_) | AttachmentInfo::Voice { .. }) {
+            return Err(Error::Other("Audio attachments cannot have a thumbnail".to_owned()));
+        }
+
+        let is_encrypted = room.is_encrypted().await;
+        let data = data.into();
+        let size = UInt::new(data.len() as u64);
+        let source = Self::upload_attachment(&appservice, is_encrypted, data, content_type, Some(filename)).await?;
+
+        let (thumbnail_source, thumbnail_info) = match thumbnail {
+            Some(thumbnail) => {
+                let content_type = thumbnail.content_type.to_string();
+                let thumbnail_info = assign!(ThumbnailInfo::new(), {
+                    height: Some(thumbnail.height),
+                    width: Some(thumbnail.width),
+                    mimetype: Some(content_type.clone()),
+                    size: Some(thumbnail.size),
+                });
+                let data = Bytes::from(thumbnail.data);
+                let source = Self::upload_attachment(&appservice, is_encrypted, data, &content_type, None).await?;
+                (Some(source), Some(Box::new(thumbnail_info)))
+            }
+            None => (None, None),
+        };
+
+        let body = filename.to_owned();
+        let mimetype = Some(content_type.to_owned());
+        let msgtype = match info {
+            AttachmentInfo::Image(_) => {
+                let info = assign!(ImageInfo::from(info), { mimetype, size, thumbnail_source, thumbnail_info });
+                MessageType::Image(ImageMessageEventContent::new(body, source).info(Box::new(info)))
+            }
+            AttachmentInfo::Video(_) => {
+                let info = assign!(VideoInfo::from(info), { mimetype, size, thumbnail_source, thumbnail_info });
+                MessageType::Video(VideoMessageEventContent::new(body, source).info(Box::new(info)))
+            }
+            AttachmentInfo::Audio(_) => {
+                let info = assign!(AudioInfo::from(info), { mimetype, size });
+                MessageType::Audio(AudioMessageEventContent::new(body, source).info(Box::new(info)))
+            }
+            AttachmentInfo::Voice { ref audio_info, ref waveform } => {
+                let details = match (audio_info.duration, waveform) {
+                    (Some(duration), Some(waveform)) => Some(UnstableAudioDetailsContentBlock::new(
+                        duration,
+                        waveform.iter().map(|amplitude| (*amplitude).into()).collect(),
+                    )),
+                    _ => None,
+                };
+                let info = assign!(AudioInfo::from(info), { mimetype, size });
+                let content = assign!(AudioMessageEventContent::new(body, source), {
+                    audio: details,
+                    voice: Some(UnstableVoiceContentBlock::new()),
+                });
+                MessageType::Audio(content.info(Box::new(info)))
+            }
+            AttachmentInfo::File(_) => {
+                let info = assign!(FileInfo::from(info), { mimetype, size, thumbnail_source, thumbnail_info });
+                let content =
+                    assign!(FileMessageEventContent::new(body, source), { filename: Some(filename.to_owned()) });
+                MessageType::File(content.info(Box::new(info)))
+            }
         };
 
-        let encoded_room_id: String = url::form_urlencoded::byte_serialize(room_id.as_bytes()).collect();
-        let txn_id = TransactionId::new().to_string();
+        self.send(&*room_id, RoomMessageEventContent::new(msgtype)).await
+    }
+
+    async fn upload_attachment(
+        appservice: &ApplicationServiceInner,
+        is_encrypted: bool,
+        data: Bytes,
+        content_type: &str,
+        filename: Option<&str>,
+    ) -> Result<MediaSource> {
+        if !is_encrypted {
+            return Ok(MediaSource::Plain(appservice.upload_media(data, content_type, filename).await?));
+        }
 
-        let payload = match room.as_ref() {
-            RoomKind::Encrypted(_) => {
-                let encrypted = self.encryption().encrypt_event(room_id, content).await?;
-                serde_json::to_value(encrypted)?
+        let (encrypted, encryption_info) = EncryptionInner::encrypt_attachment(data).await?;
+        let url = appservice.upload_media(encrypted, "application/octet-stream", None).await?;
+        let file = EncryptedFileInit {
+            url,
+            key: encryption_info.key,
+            iv: encryption_info.iv,
+            hashes: encryption_info.hashes,
+            v: encryption_info.version,
+        };
+        Ok(MediaSource::Encrypted(Box::new(file.into())))
+    }
+
+    pub async fn reply_to<'a>(
+        self: &Arc<Self>,
+        room: impl Into<&'a RoomOrAliasId>,
+        event_id: &EventId,
+        content: impl Into<RoomMessageEventContentWithoutRelation>,
+    ) -> Result<OwnedEventId> {
+        let appservice = self.appservice()?;
+        let room_id = appservice.resolve_room_id(room.into()).await?;
+        let room = appservice.get_room(&*room_id).await?.ok_or(Error::RoomNotFound(room_id.clone()))?;
+
+        let content = match room.get_decrypted_event(event_id).await {
+            Ok(original) => content.into().make_reply_to_raw(
+                original.raw(),
+                event_id.to_owned(),
+                &room_id,
+                ForwardThread::Yes,
+                AddMentions::Yes,
+            ),
+            Err(Error::UnexpectedStatus(StatusCode::NOT_FOUND | StatusCode::FORBIDDEN, _)) => {
+                tracing::debug!("Replying to unavailable event {} in room {}", event_id, room_id);
+                let mut content: RoomMessageEventContent = content.into().into();
+                content.relates_to = Some(Relation::Reply { in_reply_to: InReplyTo::new(event_id.to_owned()) });
+                content
             }
-            RoomKind::Unencrypted(_) => serde_json::to_value(content)?,
+            Err(error) => return Err(error),
         };
 
-        let url = format!(" /_matrix/client/v3/rooms/{}/send/{}/{}", encoded_room_id, room.message_type(), txn_id);
+        self.send(&*room_id, content).await
+    }
+
+    pub async fn edit_message<'a>(
+        self: &Arc<Self>,
+        room: impl Into<&'a RoomOrAliasId>,
+        event_id: &EventId,
+        new_content: impl Into<RoomMessageEventContentWithoutRelation>,
+    ) -> Result<OwnedEventId> {
+        let appservice = self.appservice()?;
+        let room_id = appservice.resolve_room_id(room.into()).await?;
+        let room = appservice.get_room(&*room_id).await?.ok_or(Error::RoomNotFound(room_id.clone()))?;
+
+        let original = room.get_decrypted_event(event_id).await?;
+        let mentions = original
+            .raw()
+            .get_field::<RoomMessageEventContent>("content")
+            .ok()
+            .flatten()
+            .and_then(|content| content.mentions);
+
+        let content =
+            new_content.into().make_replacement(ReplacementMetadata::new(event_id.to_owned(), mentions), None);
+        self.send(&*room_id, content).await
+    }
+
+    pub async fn send_in_thread<'a>(
+        self: &Arc<Self>,
+        room: impl Into<&'a RoomOrAliasId>,
+        thread_root: &EventId,
+        content: impl Into<RoomMessageEventContentWithoutRelation>,
+    ) -> Result<OwnedEventId> {
+        let appservice = self.appservice()?;
+        let room_id = appservice.resolve_room_id(room.into()).await?;
+        let room = appservice.get_room(&*room_id).await?.ok_or(Error::RoomNotFound(room_id.clone()))?;
+
+        let latest_event_id = match room.get_latest_thread_event(thread_root).await? {
+            Some(event_id) => event_id,
+            None => thread_root.to_owned(),
+        };
+
+        let mut content: RoomMessageEventContent = content.into().into();
+        content.relates_to = Some(Relation::Thread(Thread::plain(thread_root.to_owned(), latest_event_id)));
+
+        self.send(&*room_id, content).await
+    }
 
-        let response = self.client()?.put(&url).json(&payload).send().await?;
-        let send_response = parse_response::<SendResponse>(response).await?;
-        Ok(send_response.event_id)
+    pub async fn react<'a>(
+        self: &Arc<Self>,
+        room: impl Into<&'a RoomOrAliasId>,
+        event_id: &EventId,
+        key: impl Into<String>,
+    ) -> Result<OwnedEventId> {
+        let content = ReactionEventContent::new(Annotation::new(event_id.to_owned(), key.into()));
+        self.send(room, content).await
+    }
+
+    pub async fn send<'a, C>(self: &Arc<Self>, room: impl Into<&'a RoomOrAliasId>, content: C) -> Result<OwnedEventId>
+    where
+        C: MessageLikeEventContent,
+    {
+        let event_type = content.event_type().to_string();
+        self.send_raw(room, &event_type, serde_json::to_value(content)?).await
+    }
+
+    pub async fn send_raw<'a>(
+        self: &Arc<Self>,
+        room: impl Into<&'a RoomOrAliasId>,
+        event_type: &str,
+        content: Value,
+    ) -> Result<OwnedEventId> {
+        let appservice = self.user()?.appservice()?;
+        let room_id = appservice.resolve_room_id(room.into()).await?;
+        let room = match appservice.get_room(&*room_id).await? {
+            Some(room) => room.kind(),
+            None => return Err(Error::RoomNotFound(room_id)),
+        };
+
+        match room.as_ref() {
+            RoomKind::Encrypted(_) => {
+                if !appservice.supports(Feature::DeviceMasquerading) {
+                    return Err(Error::UnsupportedFeature(Feature::DeviceMasquerading));
+                }
+                let encrypted =
+                    self.encryption().encrypt_event_raw(&room_id, event_type, &Raw::new(&content)?.cast()).await?;
+                self.user()?.put_room_event(&room_id, room.message_type(), &serde_json::to_value(encrypted)?).await
+            }
+            RoomKind::Unencrypted(_) => self.user()?.put_room_event(&room_id, event_type, &content).await,
+        }
     }
 }
@@ -1,12 +1,22 @@
 use std::collections::{BTreeMap, HashSet};
-use std::path::Path;
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Weak};
 
 use bytes::Bytes;
 use matrix_sdk::SqliteCryptoStore;
 use matrix_sdk::crypto::types::events::room::encrypted::{EncryptedEvent, RoomEncryptedEventContent};
 use matrix_sdk::crypto::types::requests::{AnyOutgoingRequest, OutgoingRequest};
-use matrix_sdk::crypto::{DecryptionSettings, EncryptionSettings, EncryptionSyncChanges, OlmMachine, TrustRequirement};
+use matrix_sdk::crypto::{
+    AttachmentDecryptor,
+    AttachmentEncryptor,
+    DecryptionSettings,
+    EncryptionSettings,
+    EncryptionSyncChanges,
+    MediaEncryptionInfo,
+    OlmMachine,
+    TrustRequirement,
+};
 use matrix_sdk::deserialized_responses::DecryptedRoomEvent;
 use matrix_sdk::ruma::api::client::keys::claim_keys::v3::Response as RumaKeysClaimResponse;
 use matrix_sdk::ruma::api::client::keys::get_keys::v3::{
@@ -25,11 +35,11 @@ use matrix_sdk::ruma::api::client::to_device::send_event_to_device::v3::{
     Response as RumaToDeviceResponse,
 };
 use matrix_sdk::ruma::api::{IncomingResponse, MatrixVersion, SendAccessToken};
-use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
-use matrix_sdk::ruma::events::{AnyToDeviceEvent, EventContent};
+use matrix_sdk::ruma::events::room::EncryptedFile;
+use matrix_sdk::ruma::events::{AnyMessageLikeEventContent, AnyToDeviceEvent, EventContent};
 use matrix_sdk::ruma::exports::serde_json::json;
 use matrix_sdk::ruma::serde::Raw;
-use matrix_sdk::ruma::{OneTimeKeyAlgorithm, OwnedDeviceId, OwnedUserId, RoomId, UInt, assign};
+use matrix_sdk::ruma::{DeviceId, OneTimeKeyAlgorithm, OwnedDeviceId, OwnedUserId, RoomId, UInt, assign};
 
 use crate::appservice::ApplicationServiceInner;
 use crate::appservice::device::Device;
@@ -74,13 +84,58 @@ pub struct EncryptionInner {
 
 impl EncryptionInner {
     pub async fn new(user: &User, device_id: &OwnedDeviceId) -> Result<Arc<Self>> {
-        let db_path = Path::new(&user.appservice()?.config().database.path).join(format!("{}.db", device_id,));
+        let db_path = Self::store_path(&user.appservice()?.config().database.path, device_id);
         let store = SqliteCryptoStore::open(&db_path, Some(&user.appservice()?.config().database.passphrase)).await?;
         let olm = OlmMachine::with_store(user.id(), device_id, store, None).await?;
 
         Ok(Arc::new(Self { olm }))
     }
 
+    pub(crate) fn store_path(database_path: &str, device_id: &DeviceId) -> PathBuf {
+        Path::new(database_path).join(format!("{}.db", device_id))
+    }
+
+    pub(crate) async fn remove_store(database_path: &str, device_id: &DeviceId) -> Result<()> {
+        let db_path = Self::store_path(database_path, device_id);
+        match tokio::fs::metadata(&db_path).await {
+            Ok(metadata) if metadata.is_dir() => tokio::fs::remove_dir_all(&db_path).await?,
+            Ok(_) => tokio::fs::remove_file(&db_path).await?,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(error.into()),
+        }
+
+        tracing::info!("Removed crypto store of device {}", device_id);
+        Ok(())
+    }
+
+    pub(crate) async fn encrypt_attachment(data: Bytes) -> Result<(Vec<u8>, MediaEncryptionInfo)> {
+        let result = tokio::task::spawn_blocking(move || {
+            let mut cursor = Cursor::new(data);
+            let mut encryptor = AttachmentEncryptor::new(&mut cursor);
+            let mut encrypted = Vec::new();
+            encryptor.read_to_end(&mut encrypted)?;
+
+            Ok::<_, std::io::Error>((encrypted, encryptor.finish()))
+        })
+        .await??;
+
+        Ok(result)
+    }
+
+    pub(crate) async fn decrypt_attachment(data: Bytes, file: EncryptedFile) -> Result<Bytes> {
+        let result = tokio::task::spawn_blocking(move || {
+            let mut cursor = Cursor::new(data);
+            let mut decryptor = AttachmentDecryptor::new(&mut cursor, file.into()).map_err(std::io::Error::other)?;
+            let mut decrypted = Vec::new();
+            decryptor.read_to_end(&mut decrypted)?;
+
+            Ok::<_, std::io::Error>(Bytes::from(decrypted))
+        })
+        .await?;
+
+        result.map_err(|error| Error::DecryptAttachment(error.to_string()))
+    }
+
     pub(crate) fn upgrade(self: &Arc<Self>, device: &Arc<Device>) -> Encryption {
         Encryption { device: Arc::downgrade(device), inner: Arc::clone(self) }
     }
@@ -113,7 +168,7 @@ impl Encryption {
         let members = self
             .appservice()?
             .get_room(room_id)
-            .await
+            .await?
             .ok_or(Error::RoomNotFound(room_id.to_owned()))?
             .joined_members()
             .await;
@@ -140,7 +195,7 @@ impl Encryption {
         let members = self
             .appservice()?
             .get_room(room_id)
-            .await
+            .await?
             .ok_or(Error::RoomNotFound(room_id.to_owned()))?
             .joined_members()
             .await;
@@ -176,13 +231,14 @@ impl Encryption {
         Ok(())
     }
 
-    pub async fn encrypt_event(
+    pub async fn encrypt_event_raw(
         &self,
         room_id: &RoomId,
-        content: RoomMessageEventContent,
+        event_type: &str,
+        content: &Raw<AnyMessageLikeEventContent>,
     ) -> Result<Raw<RoomEncryptedEventContent>> {
         let appservice = self.appservice()?;
-        let room_kind = match appservice.get_room(room_id).await {
+        let room_kind = match appservice.get_room(room_id).await? {
             Some(room) => room.kind(),
             None => return Err(Error::RoomNotFound(room_id.to_owned())),
         };
@@ -193,7 +249,7 @@ impl Encryption {
 
         self.get_missing_session(room_id).await?;
         self.share_room_key(room_id).await?;
-        let encrypted = self.olm().encrypt_room_event(room_id, content).await?;
+        let encrypted = self.olm().encrypt_room_event_raw(room_id, event_type, content).await?;
 
         Ok(encrypted)
     }
@@ -204,6 +260,10 @@ impl Encryption {
         Ok(self.olm().decrypt_room_event(&event.cast(), &room_id, &decryption_settings).await?)
     }
 
+    pub async fn has_room_key(&self, event: &Raw<EncryptedEvent>, room_id: &RoomId) -> Result<bool> {
+        Ok(self.olm().is_room_key_available(event, room_id).await?)
+    }
+
     pub async fn update_tracked_users(&self, users: &HashSet<OwnedUserId>) -> Result<()> {
         Ok(self.olm().update_tracked_users(users.iter().map(OwnedUserId::as_ref)).await?)
     }
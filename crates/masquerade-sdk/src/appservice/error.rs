@@ -1,7 +1,10 @@
+use std::time::Duration;
+
 use matrix_sdk::ruma::{OwnedRoomId, OwnedUserId};
 use reqwest::StatusCode;
 use serde_json::Value;
 
+use crate::appservice::capabilities::Feature;
 use crate::appservice::encryption::OwnedEncryptionSyncChanges;
 
 pub type Result<T> = core::result::Result<T, Error>;
@@ -44,6 +47,9 @@ pub enum Error {
     #[error("Error while opening Sqlite database: {0}")]
     Sqlite(#[from] matrix_sdk_sqlite::OpenStoreError),
 
+    #[error("Error in room database: {0}")]
+    RoomDatabase(#[from] rusqlite::Error),
+
     #[error("Error occurred in Axum: {0}")]
     Axum(#[from] axum::Error),
 
@@ -62,12 +68,33 @@ pub enum Error {
     #[error("Unexpected response: {0}")]
     UnexpectedStatus(StatusCode, Value),
 
+    #[error("Invalid content URI: {0}")]
+    MxcUri(#[from] matrix_sdk::ruma::MxcUriError),
+
+    #[error(
+        "The homeserver rejected the as_token ({0}). Make sure the generated registration file is installed on the \
+         homeserver and its as_token matches the configuration"
+    )]
+    InvalidAsToken(String),
+
+    #[error(
+        "The as_token belongs to {1} instead of {0}. Make sure appservice.username matches the sender_localpart of \
+         the installed registration"
+    )]
+    AppserviceIdentity(OwnedUserId, OwnedUserId),
+
+    #[error("The homeserver does not support {0}: {hint}", hint = .0.hint())]
+    UnsupportedFeature(Feature),
+
     #[error("File not found {0}")]
     FileNotFound(String),
 
     #[error("No such user: {0}")]
     UserNotFound(OwnedUserId),
 
+    #[error("User {0} is outside of the namespaces registered by the appservice")]
+    OutsideNamespace(OwnedUserId),
+
     #[error("No device for user: {0}")]
     NoDevice(OwnedUserId),
 
@@ -77,6 +104,9 @@ pub enum Error {
     #[error("Cannot encrypt event. Room {0} is not encrypted")]
     RoomNotEncrypted(OwnedRoomId),
 
+    #[error("Invalid server ACL: {0}")]
+    InvalidServerAcl(String),
+
     #[error("Error added event handler, unknown type: {0}")]
     EventType(String),
 
@@ -86,6 +116,9 @@ pub enum Error {
     #[error("Unable to decrypted incoming event: {0}")]
     DecryptEvent(String),
 
+    #[error("Unable to decrypt attachment: {0}")]
+    DecryptAttachment(String),
+
     #[error("Attempting to run multiple sync loops. This is not allowed: {0}")]
     MultipleSync(String),
 
@@ -100,6 +133,20 @@ impl From<()> for Error {
 }
 
 impl Error {
+    pub fn errcode(&self) -> Option<&str> {
+        match self {
+            Error::UnexpectedStatus(_, body) => body["errcode"].as_str(),
+            _ => None,
+        }
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Error::UnexpectedStatus(_, body) => body["retry_after_ms"].as_u64().map(Duration::from_millis),
+            _ => None,
+        }
+    }
+
     // pub fn to_matrix_error(&self) -> MatrixError {
     //     match self {
     //         Error::Matrix(error) => error.clone(),
@@ -1,17 +1,20 @@
 use core::result::Result as StdResult;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error as StdError;
 use std::marker::PhantomData;
 use std::sync::Arc;
 
 use futures::future::BoxFuture;
 use matrix_sdk::event_handler::SyncEvent;
-use matrix_sdk::ruma::events::AnySyncTimelineEvent;
+use matrix_sdk::ruma::events::{AnySyncTimelineEvent, StaticEventContent};
+use matrix_sdk::ruma::exports::serde_json::Value;
 use matrix_sdk::ruma::serde::Raw;
 use matrix_sdk::ruma::{OwnedRoomId, OwnedUserId};
 use serde::de::DeserializeOwned;
 use tokio::sync::RwLock;
 
+use crate::appservice::member::{MemberChange, MemberChangeKind};
+use crate::appservice::room::RoomUpgrade;
 use crate::{ApplicationService, Error, Result};
 
 // #[derive(EventContent)]
@@ -21,14 +24,24 @@ use crate::{ApplicationService, Error, Result};
 // }
 
 pub type EventHandlerMap = BTreeMap<&'static str, Vec<Arc<dyn EventHandler>>>;
+pub type MemberHandlerMap = HashMap<MemberChangeKind, Vec<Arc<dyn CallbackHandler<MemberChange>>>>;
+pub type AccountDataHandlerMap = HashMap<String, Vec<Arc<dyn CallbackHandler<Value, AccountDataContext>>>>;
 
 pub struct EventHandlerStore {
     event_handlers: RwLock<EventHandlerMap>,
+    member_handlers: RwLock<MemberHandlerMap>,
+    upgrade_handlers: RwLock<Vec<Arc<dyn CallbackHandler<RoomUpgrade>>>>,
+    account_data_handlers: RwLock<AccountDataHandlerMap>,
 }
 
 impl EventHandlerStore {
     pub fn new() -> Self {
-        Self { event_handlers: RwLock::new(BTreeMap::new()) }
+        Self {
+            event_handlers: RwLock::new(BTreeMap::new()),
+            member_handlers: RwLock::new(HashMap::new()),
+            upgrade_handlers: RwLock::new(Vec::new()),
+            account_data_handlers: RwLock::new(HashMap::new()),
+        }
     }
 
     pub async fn insert<Ev, H, Fut, Err>(&self, handler: Arc<TypedEventHandler<Ev, H>>) -> Result<()>
@@ -48,6 +61,45 @@ impl EventHandlerStore {
         let handlers = self.event_handlers.read().await;
         handlers.get(event_type).cloned()
     }
+
+    pub async fn insert_member_handler(&self, kind: MemberChangeKind, handler: Arc<dyn CallbackHandler<MemberChange>>) {
+        let mut handlers = self.member_handlers.write().await;
+        handlers.entry(kind).or_default().push(handler);
+    }
+
+    pub async fn dispatch_member_change(&self, change: MemberChange, context: EventContext) {
+        let handlers = self.member_handlers.read().await.get(&change.kind).cloned().unwrap_or_default();
+        for handler in handlers {
+            handler.handle(change.clone(), context.clone()).await;
+        }
+    }
+
+    pub async fn insert_upgrade_handler(&self, handler: Arc<dyn CallbackHandler<RoomUpgrade>>) {
+        self.upgrade_handlers.write().await.push(handler);
+    }
+
+    pub async fn dispatch_room_upgrade(&self, upgrade: RoomUpgrade, context: EventContext) {
+        let handlers = self.upgrade_handlers.read().await.clone();
+        for handler in handlers {
+            handler.handle(upgrade.clone(), context.clone()).await;
+        }
+    }
+
+    pub async fn insert_account_data_handler(
+        &self,
+        event_type: &str,
+        handler: Arc<dyn CallbackHandler<Value, AccountDataContext>>,
+    ) {
+        let mut handlers = self.account_data_handlers.write().await;
+        handlers.entry(event_type.to_owned()).or_default().push(handler);
+    }
+
+    pub async fn dispatch_account_data(&self, event_type: &str, content: Value, context: AccountDataContext) {
+        let handlers = self.account_data_handlers.read().await.get(event_type).cloned().unwrap_or_default();
+        for handler in handlers {
+            handler.handle(content.clone(), context.clone()).await;
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -55,6 +107,13 @@ pub struct EventContext {
     pub room_id: OwnedRoomId,
     pub sender: OwnedUserId,
 }
+
+#[derive(Debug, Clone)]
+pub struct AccountDataContext {
+    pub user_id: OwnedUserId,
+    pub room_id: Option<OwnedRoomId>,
+}
+
 pub trait EventHandler: Send + Sync {
     fn handle(&self, raw: Raw<AnySyncTimelineEvent>, context: EventContext) -> BoxFuture<'static, ()>;
 }
@@ -89,6 +148,33 @@ where
     }
 }
 
+pub trait CallbackHandler<T, Ctx = EventContext>: Send + Sync {
+    fn handle(&self, value: T, context: Ctx) -> BoxFuture<'static, ()>;
+}
+
+pub struct TypedCallbackHandler<H> {
+    handler: H,
+}
+
+impl<T, Ctx, H, Fut, Err> CallbackHandler<T, Ctx> for TypedCallbackHandler<H>
+where
+    T: Send + 'static,
+    Ctx: Send + 'static,
+    H: Fn(T, Ctx) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = StdResult<(), Err>> + Send + 'static,
+    Err: Into<Box<dyn StdError + Send + Sync>> + 'static,
+{
+    fn handle(&self, value: T, context: Ctx) -> BoxFuture<'static, ()> {
+        let handler = self.handler.clone();
+
+        Box::pin(async move {
+            if let Err(error) = handler(value, context).await {
+                tracing::error!("Error handling callback: {}", error.into());
+            }
+        })
+    }
+}
+
 impl<Ev, H> TypedEventHandler<Ev, H>
 where
     Ev: SyncEvent,
@@ -100,8 +186,9 @@ where
 
 impl<S: Send + Sync + Clone + 'static> ApplicationService<S> {
     pub(crate) async fn add_base_handlers(&self) -> Result<()> {
-        self.add_event_handler(Self::on_stripped_room_member).await?;
+        self.add_event_handler(Self::on_room_member).await?;
         self.add_event_handler(Self::on_room_encryption).await?;
+        self.add_event_handler(Self::on_room_tombstone).await?;
         self.add_event_handler(Self::on_encrypted_message).await?;
 
         Ok(())
@@ -124,4 +211,139 @@ impl<S: Send + Sync + Clone + 'static> ApplicationService<S> {
         self.inner.handler_store().insert(handler).await?;
         Ok(self)
     }
+
+    pub async fn on_member_change<H, Fut, Err>(&self, kind: MemberChangeKind, member_handler: H) -> Result<&Self>
+    where
+        H: Fn(MemberChange, ApplicationService<S>, EventContext) -> Fut + Send + Sync + Clone + 'static,
+        Fut: Future<Output = StdResult<(), Err>> + Send + 'static,
+        Err: Into<Box<dyn StdError + Send + Sync>> + 'static,
+    {
+        let lifted_handler = {
+            let appservice = self.clone();
+            move |change: MemberChange, ctx: EventContext| member_handler(change, appservice.clone(), ctx)
+        };
+
+        let handler = Arc::new(TypedCallbackHandler { handler: lifted_handler });
+
+        self.inner.handler_store().insert_member_handler(kind, handler).await;
+        Ok(self)
+    }
+
+    pub async fn on_member_joined<H, Fut, Err>(&self, handler: H) -> Result<&Self>
+    where
+        H: Fn(MemberChange, ApplicationService<S>, EventContext) -> Fut + Send + Sync + Clone + 'static,
+        Fut: Future<Output = StdResult<(), Err>> + Send + 'static,
+        Err: Into<Box<dyn StdError + Send + Sync>> + 'static,
+    {
+        self.on_member_change(MemberChangeKind::Joined, handler).await
+    }
+
+    pub async fn on_member_left<H, Fut, Err>(&self, handler: H) -> Result<&Self>
+    where
+        H: Fn(MemberChange, ApplicationService<S>, EventContext) -> Fut + Send + Sync + Clone + 'static,
+        Fut: Future<Output = StdResult<(), Err>> + Send + 'static,
+        Err: Into<Box<dyn StdError + Send + Sync>> + 'static,
+    {
+        self.on_member_change(MemberChangeKind::Left, handler).await
+    }
+
+    pub async fn on_member_kicked<H, Fut, Err>(&self, handler: H) -> Result<&Self>
+    where
+        H: Fn(MemberChange, ApplicationService<S>, EventContext) -> Fut + Send + Sync + Clone + 'static,
+        Fut: Future<Output = StdResult<(), Err>> + Send + 'static,
+        Err: Into<Box<dyn StdError + Send + Sync>> + 'static,
+    {
+        self.on_member_change(MemberChangeKind::Kicked, handler).await
+    }
+
+    pub async fn on_member_banned<H, Fut, Err>(&self, handler: H) -> Result<&Self>
+    where
+        H: Fn(MemberChange, ApplicationService<S>, EventContext) -> Fut + Send + Sync + Clone + 'static,
+        Fut: Future<Output = StdResult<(), Err>> + Send + 'static,
+        Err: Into<Box<dyn StdError + Send + Sync>> + 'static,
+    {
+        self.on_member_change(MemberChangeKind::Banned, handler).await
+    }
+
+    pub async fn on_member_unbanned<H, Fut, Err>(&self, handler: H) -> Result<&Self>
+    where
+        H: Fn(MemberChange, ApplicationService<S>, EventContext) -> Fut + Send + Sync + Clone + 'static,
+        Fut: Future<Output = StdResult<(), Err>> + Send + 'static,
+        Err: Into<Box<dyn StdError + Send + Sync>> + 'static,
+    {
+        self.on_member_change(MemberChangeKind::Unbanned, handler).await
+    }
+
+    pub async fn on_member_invited<H, Fut, Err>(&self, handler: H) -> Result<&Self>
+    where
+        H: Fn(MemberChange, ApplicationService<S>, EventContext) -> Fut + Send + Sync + Clone + 'static,
+        Fut: Future<Output = StdResult<(), Err>> + Send + 'static,
+        Err: Into<Box<dyn StdError + Send + Sync>> + 'static,
+    {
+        self.on_member_change(MemberChangeKind::Invited, handler).await
+    }
+
+    pub async fn on_member_knocked<H, Fut, Err>(&self, handler: H) -> Result<&Self>
+    where
+        H: Fn(MemberChange, ApplicationService<S>, EventContext) -> Fut + Send + Sync + Clone + 'static,
+        Fut: Future<Output = StdResult<(), Err>> + Send + 'static,
+        Err: Into<Box<dyn StdError + Send + Sync>> + 'static,
+    {
+        self.on_member_change(MemberChangeKind::Knocked, handler).await
+    }
+
+    pub async fn on_member_profile_changed<H, Fut, Err>(&self, handler: H) -> Result<&Self>
+    where
+        H: Fn(MemberChange, ApplicationService<S>, EventContext) -> Fut + Send + Sync + Clone + 'static,
+        Fut: Future<Output = StdResult<(), Err>> + Send + 'static,
+        Err: Into<Box<dyn StdError + Send + Sync>> + 'static,
+    {
+        self.on_member_change(MemberChangeKind::ProfileChanged, handler).await
+    }
+
+    pub async fn on_room_upgraded<H, Fut, Err>(&self, upgrade_handler: H) -> Result<&Self>
+    where
+        H: Fn(RoomUpgrade, ApplicationService<S>, EventContext) -> Fut + Send + Sync + Clone + 'static,
+        Fut: Future<Output = StdResult<(), Err>> + Send + 'static,
+        Err: Into<Box<dyn StdError + Send + Sync>> + 'static,
+    {
+        let lifted_handler = {
+            let appservice = self.clone();
+            move |upgrade: RoomUpgrade, ctx: EventContext| upgrade_handler(upgrade, appservice.clone(), ctx)
+        };
+
+        let handler = Arc::new(TypedCallbackHandler { handler: lifted_handler });
+
+        self.inner.handler_store().insert_upgrade_handler(handler).await;
+        Ok(self)
+    }
+
+    // Homeservers do not push account data to appservices, so this only sees writes made through this SDK.
+    pub async fn on_local_account_data<C, H, Fut, Err>(&self, account_data_handler: H) -> Result<&Self>
+    where
+        C: StaticEventContent + DeserializeOwned + Send + 'static,
+        H: Fn(C, ApplicationService<S>, AccountDataContext) -> Fut + Send + Sync + Clone + 'static,
+        Fut: Future<Output = StdResult<(), Err>> + Send + 'static,
+        Err: Into<Box<dyn StdError + Send + Sync>> + 'static,
+    {
+        let lifted_handler = {
+            let appservice = self.clone();
+            move |content: Value, ctx: AccountDataContext| {
+                let parsed = serde_json::from_value::<C>(content);
+                let (appservice, account_data_handler) = (appservice.clone(), account_data_handler.clone());
+
+                async move {
+                    match parsed {
+                        Ok(content) => account_data_handler(content, appservice, ctx).await.map_err(Into::into),
+                        Err(error) => Err(Box::<dyn StdError + Send + Sync>::from(error)),
+                    }
+                }
+            }
+        };
+
+        let handler = Arc::new(TypedCallbackHandler { handler: lifted_handler });
+
+        self.inner.handler_store().insert_account_data_handler(C::TYPE, handler).await;
+        Ok(self)
+    }
 }
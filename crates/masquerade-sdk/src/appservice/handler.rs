@@ -1,25 +1,56 @@
 use std::borrow::Cow;
 use std::collections::HashMap;
+use std::path::Path;
 use std::sync::{Arc, Weak};
 
 use axum::Json;
+use bytes::Bytes;
+use futures::{StreamExt, stream};
+use matrix_sdk::ruma::events::room::MediaSource;
+use matrix_sdk::ruma::events::room::server_acl::RoomServerAclEventContent;
 use matrix_sdk::ruma::events::{AnySyncEphemeralRoomEvent, AnySyncTimelineEvent, AnyToDeviceEvent};
 use matrix_sdk::ruma::exports::serde_json::{Value, json};
 use matrix_sdk::ruma::serde::Raw;
-use matrix_sdk::ruma::{OwnedRoomId, OwnedTransactionId, RoomId, TransactionId, UserId};
+use matrix_sdk::ruma::{
+    MxcUri,
+    OwnedEventId,
+    OwnedMxcUri,
+    OwnedRoomId,
+    OwnedServerName,
+    OwnedTransactionId,
+    RoomAliasId,
+    RoomId,
+    RoomOrAliasId,
+    TransactionId,
+    UserId,
+};
+use regex::Regex;
 use reqwest::StatusCode;
+use reqwest::header::CONTENT_TYPE;
 use serde::Deserialize;
+use tokio::sync::OnceCell;
 
+use crate::appservice::capabilities::{Feature, ServerCapabilities};
 use crate::appservice::device::Device;
-use crate::appservice::encryption::OwnedEncryptionSyncChanges;
+use crate::appservice::encryption::{EncryptionInner, OwnedEncryptionSyncChanges};
 use crate::appservice::event_handler::EventHandlerStore;
-use crate::appservice::http_client::{Client, parse_response};
-use crate::appservice::room::{Room, RoomStore};
+use crate::appservice::http_client::{Client, discard_response, encode_path_segment, error_for_status, parse_response};
+use crate::appservice::outgoing_queue::OutgoingQueue;
+use crate::appservice::room::{MAX_CONCURRENT_ROOMS, Room, RoomStore};
+use crate::appservice::room_database::RoomDatabase;
 use crate::appservice::transaction::TransactionLog;
-use crate::appservice::types::{Config, Ping, Transaction};
+use crate::appservice::types::{
+    Config,
+    CreateAliasRequest,
+    Ping,
+    Profile,
+    ResolveAliasResponse,
+    Transaction,
+    UploadResponse,
+};
 use crate::appservice::user::{User, UserStore};
 use crate::appservice::{ApplicationServiceInner, EventContext};
-use crate::{Error, PingResponse, Result};
+use crate::{Error, PingResponse, Result, VersionsResponse, WhoamiResponse};
 
 pub trait ApplicationServiceReference {
     fn appservice(&self) -> Result<Arc<ApplicationServiceInner>>;
@@ -33,14 +64,19 @@ impl ApplicationServiceInner {
         let mxid = UserId::parse(format!("@{}:{}", &config.appservice.username, &config.homeserver.server_name))?;
 
         let client = Arc::new(Client::new(&config)?);
+        let room_database =
+            RoomDatabase::open(Path::new(&config.database.path).join("appservice").join("rooms.db")).await?;
+        let persist_outgoing = config.database.persist_outgoing;
         let inner = Arc::new_cyclic(|weak_ref| Self {
             mxid: mxid.clone(),
             config,
             client,
             user_store: UserStore::new(Weak::clone(weak_ref)),
-            room_store: RoomStore::new(Weak::clone(weak_ref)),
+            outgoing_queue: OutgoingQueue::new(Weak::clone(weak_ref), persist_outgoing.then(|| room_database.clone())),
+            room_store: RoomStore::new(Weak::clone(weak_ref), room_database),
             handler_store: EventHandlerStore::new(),
             transaction_log: TransactionLog::new(),
+            capabilities: OnceCell::new(),
         });
 
         Ok(inner)
@@ -70,27 +106,46 @@ impl ApplicationServiceInner {
         &self.transaction_log
     }
 
+    pub fn outgoing_queue(&self) -> &OutgoingQueue {
+        &self.outgoing_queue
+    }
+
     pub async fn run(self: &Arc<Self>) -> Result<()> {
         self.ping().await?;
+        self.self_check().await?;
+        self.room_store.load().await?;
+        self.outgoing_queue.resume().await?;
 
         tracing::info!("Initializing user {}", &self.mxid);
         let bot_user = self.create_user(self.mxid.as_str()).await?;
         let bot_device = bot_user.create_device(None).await?;
 
         let displayname = self.config.appservice.displayname.clone();
-        match bot_user.get_profile().await {
+        let profile = match bot_user.get_profile().await {
             Ok(profile) => {
-                if let Some(current_displayname) = profile.displayname
-                    && current_displayname != displayname
+                if let Some(current_displayname) = &profile.displayname
+                    && *current_displayname != displayname
                 {
                     bot_user.set_displayname(&displayname).await?;
                 }
+                profile
             }
             Err(_) => {
                 bot_user.register().await?;
                 bot_user.set_displayname(&displayname).await?;
                 bot_device.register(Some(self.config.appservice.displayname.clone())).await?;
+                Profile::default()
             }
+        };
+
+        if let Some(avatar_url) = &self.config.appservice.avatar_url
+            && profile.avatar_url.as_ref() != Some(avatar_url)
+        {
+            bot_user.set_avatar_url(avatar_url).await?;
+        }
+
+        if let Err(error) = bot_user.remove_orphaned_devices().await {
+            tracing::warn!("Unable to remove orphaned devices of {}: {}", bot_user.id(), error);
         }
 
         if let Err(error) = bot_device.run().await {
@@ -112,6 +167,42 @@ impl ApplicationServiceInner {
         Ok(())
     }
 
+    pub async fn self_check(&self) -> Result<()> {
+        let response = self.client.get("/_matrix/client/v3/account/whoami").send().await?;
+        let whoami: WhoamiResponse = match parse_response(response).await {
+            Ok(whoami) => whoami,
+            Err(Error::UnexpectedStatus(status, body))
+                if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN =>
+            {
+                let reason = body["errcode"].as_str().unwrap_or(status.as_str()).to_owned();
+                return Err(Error::InvalidAsToken(reason));
+            }
+            Err(error) => return Err(error),
+        };
+
+        if whoami.user_id != self.mxid {
+            return Err(Error::AppserviceIdentity(self.mxid.clone(), whoami.user_id));
+        }
+
+        let response = self.client.get("/_matrix/client/versions").send().await?;
+        let capabilities = ServerCapabilities::from(parse_response::<VersionsResponse>(response).await?);
+        for feature in capabilities.missing() {
+            tracing::warn!("Homeserver does not advertise {}: {}", feature, feature.hint());
+        }
+
+        tracing::info!("Homeserver supports spec versions {}", capabilities.versions.join(", "));
+        let _ = self.capabilities.set(capabilities);
+        Ok(())
+    }
+
+    pub fn capabilities(&self) -> Option<&ServerCapabilities> {
+        self.capabilities.get()
+    }
+
+    pub fn supports(&self, feature: Feature) -> bool {
+        self.capabilities.get().is_none_or(|capabilities| capabilities.supports(feature))
+    }
+
     pub async fn handle_ping(&self, _: Ping) -> (StatusCode, Json<Value>) {
         (StatusCode::OK, Json(json!({})))
     }
@@ -222,8 +313,137 @@ impl ApplicationServiceInner {
         Ok((transaction.events, transaction.ephemeral))
     }
 
-    pub async fn get_room(&self, room_id: &RoomId) -> Option<Arc<Room>> {
-        self.room_store().get(room_id).await
+    pub async fn get_room<'a>(&self, room: impl Into<&'a RoomOrAliasId>) -> Result<Option<Arc<Room>>> {
+        let room_id = self.resolve_room_id(room.into()).await?;
+        Ok(self.room_store().get(&room_id).await)
+    }
+
+    pub async fn resolve_room_id(&self, room: &RoomOrAliasId) -> Result<OwnedRoomId> {
+        match <&RoomId>::try_from(room) {
+            Ok(room_id) => Ok(room_id.to_owned()),
+            Err(alias) => self.resolve_alias(alias).await,
+        }
+    }
+
+    pub async fn resolve_alias(&self, alias: &RoomAliasId) -> Result<OwnedRoomId> {
+        tracing::debug!("Resolving room alias {}", alias);
+        let url = format!("/_matrix/client/v3/directory/room/{}", encode_path_segment(alias.as_str()));
+        let response = self.client.get(&url).send().await?;
+        let json: ResolveAliasResponse = parse_response(response).await?;
+
+        Ok(json.room_id)
+    }
+
+    pub async fn create_alias(&self, alias: &RoomAliasId, room_id: &RoomId) -> Result<()> {
+        tracing::info!("Creating room alias {} for room {}", alias, room_id);
+        let url = format!("/_matrix/client/v3/directory/room/{}", encode_path_segment(alias.as_str()));
+        let body = CreateAliasRequest { room_id };
+        let response = self.client.put(&url).json(&body).send().await?;
+
+        discard_response(response).await
+    }
+
+    pub async fn delete_alias(&self, alias: &RoomAliasId) -> Result<()> {
+        tracing::info!("Deleting room alias {}", alias);
+        let url = format!("/_matrix/client/v3/directory/room/{}", encode_path_segment(alias.as_str()));
+        let response = self.client.delete(&url).send().await?;
+
+        discard_response(response).await
+    }
+
+    pub async fn follow_room_upgrade(
+        &self,
+        predecessor: &RoomId,
+        successor: &RoomId,
+        via: &[OwnedServerName],
+    ) -> Result<Arc<Room>> {
+        tracing::info!("Room {} was upgraded to {}", predecessor, successor);
+        let old_room = self.room_store().get(predecessor).await.ok_or(Error::RoomNotFound(predecessor.to_owned()))?;
+
+        let mut joined = Vec::new();
+        for user in old_room.get_appservice_users().await? {
+            match user.join_room_via(successor, via).await {
+                Ok(_) => joined.push(user),
+                Err(error) => tracing::warn!("Unable to join {} to upgraded room {}: {}", user.id(), successor, error),
+            }
+        }
+
+        if joined.is_empty() {
+            return Err(Error::Other(format!("No appservice user was able to join upgraded room {}", successor)));
+        }
+
+        let room = self.room_store().link_upgrade(predecessor, successor).await?;
+        let carried = self.room_store().carried_account_data().await;
+        for user in &joined {
+            if let Err(error) = user.replace_direct_room(predecessor, successor).await {
+                tracing::warn!("Unable to move direct chat of {} to room {}: {}", user.id(), successor, error);
+            }
+            for event_type in &carried {
+                if let Err(error) = user.copy_room_account_data(predecessor, successor, event_type).await {
+                    tracing::warn!("Unable to copy {} of {} to room {}: {}", event_type, user.id(), successor, error);
+                }
+            }
+        }
+
+        Ok(room)
+    }
+
+    pub async fn update_server_acls<F>(
+        &self,
+        sender: &User,
+        rooms: &[OwnedRoomId],
+        update: F,
+    ) -> Vec<(OwnedRoomId, Result<OwnedEventId>)>
+    where
+        F: Fn(&mut RoomServerAclEventContent),
+    {
+        let futures = rooms.iter().map(async |room_id| {
+            let result = match self.room_store().get(room_id).await {
+                Some(room) => room.update_server_acl(sender, &update).await,
+                None => Err(Error::RoomNotFound(room_id.clone())),
+            };
+
+            (room_id.clone(), result)
+        });
+
+        stream::iter(futures).buffer_unordered(MAX_CONCURRENT_ROOMS).collect().await
+    }
+
+    pub async fn upload_media(
+        &self,
+        bytes: impl Into<Bytes>,
+        content_type: &str,
+        filename: Option<&str>,
+    ) -> Result<OwnedMxcUri> {
+        tracing::info!("Uploading media of type {}", content_type);
+        let mut request = self.client.post("/_matrix/media/v3/upload").header(CONTENT_TYPE, content_type);
+        if let Some(filename) = filename {
+            request = request.query(&[("filename", filename)]);
+        }
+
+        let response = request.body(bytes.into()).send().await?;
+        let json: UploadResponse = parse_response(response).await?;
+
+        Ok(json.content_uri)
+    }
+
+    pub async fn download_media(&self, mxc: &MxcUri) -> Result<Bytes> {
+        tracing::debug!("Downloading media {}", mxc);
+        let (server_name, media_id) = mxc.parts()?;
+        let url = format!("/_matrix/client/v1/media/download/{}/{}", server_name, encode_path_segment(media_id));
+        let response = error_for_status(self.client.get(&url).send().await?).await?;
+
+        Ok(response.bytes().await?)
+    }
+
+    pub async fn download_attachment(&self, source: &MediaSource) -> Result<Bytes> {
+        match source {
+            MediaSource::Plain(mxc) => self.download_media(mxc).await,
+            MediaSource::Encrypted(file) => {
+                let data = self.download_media(&file.url).await?;
+                EncryptionInner::decrypt_attachment(data, (**file).clone()).await
+            }
+        }
     }
 
     pub async fn get_user(&self, mxid: &str) -> Option<Arc<User>> {
@@ -245,29 +465,101 @@ impl ApplicationServiceInner {
     }
 
     pub async fn ensure_device(self: &Arc<Self>, mxid: &str, device_id: &str) -> Result<Arc<Device>> {
-        let device = match self.get_user(mxid).await {
-            Some(user) => match user.get_device().await {
-                Some(device) => device,
-                None => user.create_device(Some(device_id)).await?,
-            },
-            None => {
-                let user = self.create_user(mxid).await?;
-                let device = user.create_device(Some(device_id)).await?;
-
-                tokio::spawn({
-                    let device = Arc::clone(&device);
-                    async move {
-                        if let Err(error) = device.run().await {
-                            tracing::error!("Error in main loop for device {}: {}", device.id(), error)
-                        }
-                    }
-                });
+        let user = match self.get_user(mxid).await {
+            Some(user) => user,
+            None => self.create_user(mxid).await?,
+        };
+
+        if let Some(device) = user.get_device_by_id(device_id.into()).await {
+            return Ok(device);
+        }
+
+        let device = user.create_device(Some(device_id)).await?;
+        Self::spawn_sync_loop(&device);
+
+        Ok(device)
+    }
+
+    pub async fn ensure_puppet(self: &Arc<Self>, localpart: &str, profile: Profile) -> Result<Arc<User>> {
+        let mxid = UserId::parse_with_server_name(localpart, &self.config.homeserver.server_name)?;
+        if !self.is_in_namespace(&mxid) {
+            return Err(Error::OutsideNamespace(mxid));
+        }
 
-                device
+        let _guard = self.user_store.lock_puppet(&mxid).await;
+        self.setup_puppet(&mxid, profile).await
+    }
+
+    async fn setup_puppet(self: &Arc<Self>, mxid: &UserId, profile: Profile) -> Result<Arc<User>> {
+        let user = match self.user_store.get(mxid).await {
+            Some(user) => user,
+            None => self.create_user(mxid.as_str()).await?,
+        };
+
+        let current = match user.get_profile().await {
+            Ok(current) => current,
+            Err(Error::UnexpectedStatus(StatusCode::NOT_FOUND, _)) => {
+                match user.register().await {
+                    Ok(_) => (),
+                    Err(Error::UnexpectedStatus(_, body)) if body["errcode"] == "M_USER_IN_USE" => {
+                        tracing::debug!("User {} is already registered", mxid)
+                    }
+                    Err(error) => return Err(error),
+                }
+                Profile::default()
             }
+            Err(error) => return Err(error),
         };
 
-        Ok(device)
+        if let Some(displayname) = &profile.displayname
+            && current.displayname.as_ref() != Some(displayname)
+        {
+            user.set_displayname(displayname).await?;
+        }
+
+        if let Some(avatar_url) = &profile.avatar_url
+            && current.avatar_url.as_ref() != Some(avatar_url)
+        {
+            user.set_avatar_url(avatar_url).await?;
+        }
+
+        if user.get_device().await.is_none() {
+            let device = user.create_device(None).await?;
+            device.register(profile.displayname).await?;
+            Self::spawn_sync_loop(&device);
+        }
+
+        if let Err(error) = user.remove_orphaned_devices().await {
+            tracing::warn!("Unable to remove orphaned devices of {}: {}", user.id(), error);
+        }
+
+        Ok(user)
+    }
+
+    pub fn is_in_namespace(&self, mxid: &UserId) -> bool {
+        self.user_namespaces().iter().any(|namespace| match Regex::new(namespace) {
+            Ok(regex) => regex.is_match(mxid.as_str()),
+            Err(error) => {
+                tracing::warn!("Ignoring invalid user namespace {}: {}", namespace, error);
+                false
+            }
+        })
+    }
+
+    pub fn user_namespaces(&self) -> Vec<String> {
+        let bot_namespace = format!("^{}$", regex::escape(self.mxid.as_str()));
+        std::iter::once(bot_namespace).chain(self.config.appservice.user_namespaces.iter().cloned()).collect()
+    }
+
+    fn spawn_sync_loop(device: &Arc<Device>) {
+        tokio::spawn({
+            let device = Arc::clone(device);
+            async move {
+                if let Err(error) = device.run().await {
+                    tracing::error!("Error in main loop for device {}: {}", device.id(), error)
+                }
+            }
+        });
     }
 
     pub async fn init_bot(self: &Arc<Self>) -> Result<()> {
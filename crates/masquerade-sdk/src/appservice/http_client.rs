@@ -1,6 +1,8 @@
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
 use reqwest::header::{AUTHORIZATION, HeaderMap, HeaderValue};
 use reqwest::{Error, IntoUrl, Method, Request, RequestBuilder, Response};
 use serde::de::DeserializeOwned;
+use serde_json::Value;
 use url::Url;
 
 use crate::Result;
@@ -73,19 +75,32 @@ fn join_url(base_url: &Url, url: &str) -> Url {
     base_url.join(url).unwrap_or(base_url.to_owned())
 }
 
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+pub fn encode_path_segment(segment: &str) -> String {
+    utf8_percent_encode(segment, PATH_SEGMENT).to_string()
+}
+
 pub async fn parse_response<T>(response: Response) -> Result<T>
 where
     T: DeserializeOwned,
 {
-    match response.error_for_status() {
-        Ok(response) => Ok(response.json().await?),
-        Err(error) => Err(error.into()),
-    }
+    let response = error_for_status(response).await?;
+    Ok(response.json().await?)
 }
 
 pub async fn discard_response(response: Response) -> Result<()> {
-    match response.error_for_status() {
-        Ok(_) => Ok(()),
-        Err(error) => Err(error.into()),
+    error_for_status(response).await?;
+    Ok(())
+}
+
+pub async fn error_for_status(response: Response) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
     }
+
+    let body = response.bytes().await?;
+    let body = serde_json::from_slice(&body).unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&body).into()));
+    Err(crate::Error::UnexpectedStatus(status, body))
 }
@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, PoisonError};
+
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+type LockMap<K> = Arc<std::sync::Mutex<HashMap<K, Arc<Mutex<()>>>>>;
+
+#[derive(Debug)]
+pub struct KeyedLock<K> {
+    locks: LockMap<K>,
+}
+
+impl<K: Eq + Hash + Clone> KeyedLock<K> {
+    pub fn new() -> Self {
+        Self { locks: Arc::new(std::sync::Mutex::new(HashMap::new())) }
+    }
+
+    pub async fn lock(&self, key: &K) -> KeyedLockGuard<K> {
+        let lock = {
+            let mut locks = self.locks.lock().unwrap_or_else(PoisonError::into_inner);
+            Arc::clone(locks.entry(key.clone()).or_default())
+        };
+        let guard = Arc::clone(&lock).lock_owned().await;
+
+        KeyedLockGuard { locks: Arc::clone(&self.locks), key: key.clone(), lock, guard: Some(guard) }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.locks.lock().unwrap_or_else(PoisonError::into_inner).len()
+    }
+}
+
+pub struct KeyedLockGuard<K: Eq + Hash> {
+    locks: LockMap<K>,
+    key: K,
+    lock: Arc<Mutex<()>>,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl<K: Eq + Hash> Drop for KeyedLockGuard<K> {
+    fn drop(&mut self) {
+        self.guard.take();
+
+        // Once only the map and this guard hold the lock, nobody is waiting for the key and the entry can go.
+        let mut locks = self.locks.lock().unwrap_or_else(PoisonError::into_inner);
+        if Arc::strong_count(&self.lock) == 2 {
+            locks.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn removes_idle_keys() {
+        let locks = KeyedLock::new();
+        let guard = locks.lock(&"a").await;
+        assert_eq!(locks.len(), 1);
+
+        drop(guard);
+        assert_eq!(locks.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn keeps_keys_with_waiters() {
+        let locks = Arc::new(KeyedLock::new());
+        let guard = locks.lock(&"a").await;
+
+        let waiter = tokio::spawn({
+            let locks = Arc::clone(&locks);
+            async move {
+                let _guard = locks.lock(&"a").await;
+            }
+        });
+        while Arc::strong_count(&guard.lock) < 3 {
+            tokio::task::yield_now().await;
+        }
+
+        drop(guard);
+        waiter.await.unwrap();
+        assert_eq!(locks.len(), 0);
+    }
+}
@@ -0,0 +1,159 @@
+use matrix_sdk::ruma::events::room::member::{
+    MembershipChange,
+    MembershipState,
+    RedactedRoomMemberEventContent,
+    RoomMemberEventContent,
+};
+use matrix_sdk::ruma::{OwnedMxcUri, OwnedUserId};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoomMember {
+    pub membership: MembershipState,
+    pub displayname: Option<String>,
+    pub avatar_url: Option<OwnedMxcUri>,
+}
+
+impl RoomMember {
+    pub fn is_joined(&self) -> bool {
+        self.membership == MembershipState::Join
+    }
+
+    pub(crate) fn to_content(&self) -> RoomMemberEventContent {
+        let mut content = RoomMemberEventContent::new(self.membership.clone());
+        content.displayname = self.displayname.clone();
+        content.avatar_url = self.avatar_url.clone();
+        content
+    }
+}
+
+impl From<&RoomMemberEventContent> for RoomMember {
+    fn from(content: &RoomMemberEventContent) -> Self {
+        Self {
+            membership: content.membership.clone(),
+            displayname: content.displayname.clone(),
+            avatar_url: content.avatar_url.clone(),
+        }
+    }
+}
+
+impl From<&RedactedRoomMemberEventContent> for RoomMember {
+    fn from(content: &RedactedRoomMemberEventContent) -> Self {
+        Self { membership: content.membership.clone(), displayname: None, avatar_url: None }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MemberChangeKind {
+    Joined,
+    Left,
+    Kicked,
+    Banned,
+    Unbanned,
+    Invited,
+    InvitationRejected,
+    InvitationRevoked,
+    Knocked,
+    KnockRetracted,
+    KnockDenied,
+    ProfileChanged,
+}
+
+impl MemberChangeKind {
+    pub(crate) fn from_change(change: &MembershipChange<'_>) -> Option<Self> {
+        let kind = match change {
+            MembershipChange::Joined | MembershipChange::InvitationAccepted => MemberChangeKind::Joined,
+            MembershipChange::Left => MemberChangeKind::Left,
+            MembershipChange::Kicked => MemberChangeKind::Kicked,
+            MembershipChange::Banned | MembershipChange::KickedAndBanned => MemberChangeKind::Banned,
+            MembershipChange::Unbanned => MemberChangeKind::Unbanned,
+            MembershipChange::Invited | MembershipChange::KnockAccepted => MemberChangeKind::Invited,
+            MembershipChange::InvitationRejected => MemberChangeKind::InvitationRejected,
+            MembershipChange::InvitationRevoked => MemberChangeKind::InvitationRevoked,
+            MembershipChange::Knocked => MemberChangeKind::Knocked,
+            MembershipChange::KnockRetracted => MemberChangeKind::KnockRetracted,
+            MembershipChange::KnockDenied => MemberChangeKind::KnockDenied,
+            MembershipChange::ProfileChanged { .. } => MemberChangeKind::ProfileChanged,
+            _ => return None,
+        };
+
+        Some(kind)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MemberChange {
+    pub user_id: OwnedUserId,
+    pub kind: MemberChangeKind,
+    pub previous: Option<RoomMember>,
+    pub current: RoomMember,
+    pub reason: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::{UserId, user_id};
+
+    use super::*;
+
+    fn member(membership: MembershipState) -> RoomMember {
+        RoomMember { membership, displayname: None, avatar_url: None }
+    }
+
+    fn kind(
+        previous: Option<RoomMember>,
+        current: RoomMember,
+        sender: &UserId,
+        target: &UserId,
+    ) -> Option<MemberChangeKind> {
+        let previous = previous.as_ref().map(RoomMember::to_content);
+        let current = current.to_content();
+        let change = current.membership_change(previous.as_ref().map(RoomMemberEventContent::details), sender, target);
+        MemberChangeKind::from_change(&change)
+    }
+
+    #[test]
+    fn classifies_joins_and_leaves() {
+        let alice = user_id!("@alice:example.org");
+
+        assert_eq!(kind(None, member(MembershipState::Join), alice, alice), Some(MemberChangeKind::Joined));
+        assert_eq!(
+            kind(Some(member(MembershipState::Invite)), member(MembershipState::Join), alice, alice),
+            Some(MemberChangeKind::Joined)
+        );
+        assert_eq!(
+            kind(Some(member(MembershipState::Join)), member(MembershipState::Leave), alice, alice),
+            Some(MemberChangeKind::Left)
+        );
+    }
+
+    #[test]
+    fn classifies_moderation() {
+        let moderator = user_id!("@mod:example.org");
+        let alice = user_id!("@alice:example.org");
+
+        assert_eq!(
+            kind(Some(member(MembershipState::Join)), member(MembershipState::Leave), moderator, alice),
+            Some(MemberChangeKind::Kicked)
+        );
+        assert_eq!(
+            kind(Some(member(MembershipState::Join)), member(MembershipState::Ban), moderator, alice),
+            Some(MemberChangeKind::Banned)
+        );
+        assert_eq!(
+            kind(Some(member(MembershipState::Ban)), member(MembershipState::Leave), moderator, alice),
+            Some(MemberChangeKind::Unbanned)
+        );
+    }
+
+    #[test]
+    fn classifies_profile_changes() {
+        let alice = user_id!("@alice:example.org");
+        let renamed = RoomMember { displayname: Some("Alice".to_owned()), ..member(MembershipState::Join) };
+
+        assert_eq!(
+            kind(Some(member(MembershipState::Join)), renamed, alice, alice),
+            Some(MemberChangeKind::ProfileChanged)
+        );
+        assert_eq!(kind(Some(member(MembershipState::Join)), member(MembershipState::Join), alice, alice), None);
+    }
+}
@@ -0,0 +1,229 @@
+use matrix_sdk::ruma::events::Mentions;
+use matrix_sdk::ruma::events::room::message::{
+    FormattedBody,
+    MessageType,
+    NoticeMessageEventContent,
+    RoomMessageEventContent,
+    RoomMessageEventContentWithoutRelation,
+    TextMessageEventContent,
+};
+use matrix_sdk::ruma::{OwnedServerName, RoomAliasId, RoomId, UserId};
+
+#[derive(Debug, Clone, Default)]
+pub struct MessageBuilder {
+    body: String,
+    html: String,
+    formatted: bool,
+    notice: bool,
+    mentions: Mentions,
+}
+
+impl MessageBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn notice() -> Self {
+        Self { notice: true, ..Self::default() }
+    }
+
+    pub fn text(mut self, text: &str) -> Self {
+        self.body.push_str(text);
+        self.html.push_str(&escape_html(text).replace('\n', "<br>"));
+        self
+    }
+
+    pub fn markdown(mut self, markdown: &str) -> Self {
+        self.body.push_str(markdown);
+        match FormattedBody::markdown(markdown) {
+            Some(formatted) => {
+                self.html.push_str(&formatted.body);
+                self.formatted = true;
+            }
+            None => self.html.push_str(&escape_html(markdown).replace('\n', "<br>")),
+        }
+        self
+    }
+
+    pub fn line_break(mut self) -> Self {
+        self.body.push('\n');
+        self.html.push_str("<br>");
+        self
+    }
+
+    pub fn mention(mut self, user_id: &UserId, display_name: Option<&str>) -> Self {
+        let name = display_name.unwrap_or(user_id.as_str());
+        self.body.push_str(name);
+        self.link(&user_id.matrix_to_uri().to_string(), name);
+        self.mentions.user_ids.insert(user_id.to_owned());
+        self
+    }
+
+    pub fn mention_room(mut self) -> Self {
+        self.body.push_str("@room");
+        self.html.push_str("@room");
+        self.mentions.room = true;
+        self
+    }
+
+    pub fn room_link(mut self, room_id: &RoomId, via: &[OwnedServerName]) -> Self {
+        self.body.push_str(room_id.as_str());
+        self.link(&room_id.matrix_to_uri_via(via.to_vec()).to_string(), room_id.as_str());
+        self
+    }
+
+    pub fn room_alias_link(mut self, alias: &RoomAliasId) -> Self {
+        self.body.push_str(alias.as_str());
+        self.link(&alias.matrix_to_uri().to_string(), alias.as_str());
+        self
+    }
+
+    pub fn code(mut self, code: &str) -> Self {
+        self.body.push('`');
+        self.body.push_str(code);
+        self.body.push('`');
+        self.html.push_str(&format!("<code>{}</code>", escape_html(code)));
+        self.formatted = true;
+        self
+    }
+
+    pub fn code_block(mut self, language: Option<&str>, code: &str) -> Self {
+        let code = code.trim_end_matches('\n');
+        self.body.push_str(&format!("```{}\n{}\n```\n", language.unwrap_or_default(), code));
+        match language {
+            Some(language) => self.html.push_str(&format!(
+                "<pre><code class=\"language-{}\">{}\n</code></pre>",
+                escape_html(language),
+                escape_html(code)
+            )),
+            None => self.html.push_str(&format!("<pre><code>{}\n</code></pre>", escape_html(code))),
+        }
+        self.formatted = true;
+        self
+    }
+
+    pub fn build(self) -> RoomMessageEventContent {
+        let msgtype = match (self.notice, self.formatted) {
+            (true, true) => MessageType::Notice(NoticeMessageEventContent::html(self.body, self.html)),
+            (true, false) => MessageType::Notice(NoticeMessageEventContent::plain(self.body)),
+            (false, true) => MessageType::Text(TextMessageEventContent::html(self.body, self.html)),
+            (false, false) => MessageType::Text(TextMessageEventContent::plain(self.body)),
+        };
+
+        let mut content = RoomMessageEventContent::new(msgtype);
+        content.mentions = Some(self.mentions);
+        content
+    }
+
+    fn link(&mut self, href: &str, text: &str) {
+        self.html.push_str(&format!("<a href=\"{}\">{}</a>", escape_html(href), escape_html(text)));
+        self.formatted = true;
+    }
+}
+
+impl From<MessageBuilder> for RoomMessageEventContent {
+    fn from(builder: MessageBuilder) -> Self {
+        builder.build()
+    }
+}
+
+impl From<MessageBuilder> for RoomMessageEventContentWithoutRelation {
+    fn from(builder: MessageBuilder) -> Self {
+        builder.build().into()
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        match character {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(character),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::{room_alias_id, user_id};
+
+    use super::*;
+
+    fn text_content(content: &RoomMessageEventContent) -> (&str, Option<&str>) {
+        match &content.msgtype {
+            MessageType::Text(text) => (&text.body, text.formatted.as_ref().map(|formatted| formatted.body.as_str())),
+            MessageType::Notice(notice) => {
+                (&notice.body, notice.formatted.as_ref().map(|formatted| formatted.body.as_str()))
+            }
+            _ => panic!("unexpected message type"),
+        }
+    }
+
+    #[test]
+    fn escapes_html() {
+        assert_eq!(
+            escape_html("<a href=\"x\">Tom & 'Jerry'</a>"),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; &#39;Jerry&#39;&lt;/a&gt;"
+        );
+        assert_eq!(escape_html("plain text"), "plain text");
+    }
+
+    #[test]
+    fn plain_text_stays_unformatted() {
+        let content = MessageBuilder::new().text("a < b").line_break().text("c").build();
+        assert_eq!(text_content(&content), ("a < b\nc", None));
+    }
+
+    #[test]
+    fn mentions_are_linked_and_recorded() {
+        let alice = user_id!("@alice:example.org");
+        let content = MessageBuilder::new().text("Hi ").mention(alice, Some("<Alice>")).build();
+
+        assert_eq!(
+            text_content(&content),
+            ("Hi <Alice>", Some("Hi <a href=\"https://matrix.to/#/@alice:example.org\">&lt;Alice&gt;</a>"))
+        );
+        let mentions = content.mentions.unwrap();
+        assert!(mentions.user_ids.contains(alice));
+        assert!(!mentions.room);
+    }
+
+    #[test]
+    fn notices_with_code_are_formatted() {
+        let content = MessageBuilder::notice()
+            .code("x<y")
+            .text(" in ")
+            .room_alias_link(room_alias_id!("#room:example.org"))
+            .mention_room()
+            .build();
+
+        assert!(matches!(content.msgtype, MessageType::Notice(_)));
+        assert_eq!(
+            text_content(&content),
+            (
+                "`x<y` in #room:example.org@room",
+                Some(
+                    "<code>x&lt;y</code> in <a href=\"https://matrix.to/#/%23room:example.org\">#room:example.org</a>@room"
+                )
+            )
+        );
+        assert!(content.mentions.unwrap().room);
+    }
+
+    #[test]
+    fn code_blocks_keep_language() {
+        let content = MessageBuilder::new().code_block(Some("rust"), "let a = 1 < 2;\n").build();
+        assert_eq!(
+            text_content(&content),
+            (
+                "```rust\nlet a = 1 < 2;\n```\n",
+                Some("<pre><code class=\"language-rust\">let a = 1 &lt; 2;\n</code></pre>")
+            )
+        );
+    }
+}
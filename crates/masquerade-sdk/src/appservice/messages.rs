@@ -0,0 +1,198 @@
+use matrix_sdk::ruma::api::client::filter::RoomEventFilter;
+use matrix_sdk::ruma::events::{AnySyncStateEvent, AnySyncTimelineEvent};
+use matrix_sdk::ruma::serde::Raw;
+
+use crate::appservice::room::Direction;
+
+const MAX_PAGE_SIZE: usize = 100;
+
+#[derive(Debug, Clone)]
+pub struct MessagesRequest {
+    direction: Direction,
+    from: Option<String>,
+    to: Option<String>,
+    limit: Option<usize>,
+    filter: Option<RoomEventFilter>,
+    decrypt: bool,
+}
+
+impl MessagesRequest {
+    pub fn new(direction: Direction) -> Self {
+        Self { direction, from: None, to: None, limit: None, filter: None, decrypt: false }
+    }
+
+    pub fn forward() -> Self {
+        Self::new(Direction::Forward)
+    }
+
+    pub fn backward() -> Self {
+        Self::new(Direction::Backward)
+    }
+
+    pub fn from_token(mut self, token: impl Into<String>) -> Self {
+        self.from = Some(token.into());
+        self
+    }
+
+    pub fn to_token(mut self, token: impl Into<String>) -> Self {
+        self.to = Some(token.into());
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = (limit > 0).then_some(limit);
+        self
+    }
+
+    pub fn filter(mut self, filter: RoomEventFilter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    pub fn decrypt(mut self, decrypt: bool) -> Self {
+        self.decrypt = decrypt;
+        self
+    }
+
+    pub fn should_decrypt(&self) -> bool {
+        self.decrypt
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    pub(crate) fn advance(&mut self, token: String) -> bool {
+        if self.from.as_ref() == Some(&token) {
+            return false;
+        }
+
+        self.from = Some(token);
+        true
+    }
+
+    pub(crate) fn remaining(&self, fetched: usize) -> Option<usize> {
+        self.limit.map(|limit| limit.saturating_sub(fetched))
+    }
+
+    pub(crate) fn query(&self, fetched: usize) -> serde_json::Result<Vec<(&'static str, String)>> {
+        let mut params = vec![("dir", self.direction.to_string())];
+        if let Some(from) = &self.from {
+            params.push(("from", from.clone()));
+        }
+        if let Some(to) = &self.to {
+            params.push(("to", to.clone()));
+        }
+        if let Some(remaining) = self.remaining(fetched) {
+            params.push(("limit", remaining.min(MAX_PAGE_SIZE).to_string()));
+        }
+        if let Some(filter) = &self.filter {
+            params.push(("filter", serde_json::to_string(filter)?));
+        }
+
+        Ok(params)
+    }
+}
+
+impl From<Direction> for MessagesRequest {
+    fn from(direction: Direction) -> Self {
+        Self::new(direction)
+    }
+}
+
+#[derive(Debug)]
+pub struct MessagesPage<T = Raw<AnySyncTimelineEvent>> {
+    pub chunk: Vec<T>,
+    pub end: Option<String>,
+}
+
+impl<T> MessagesPage<T> {
+    pub fn is_exhausted(&self) -> bool {
+        self.end.is_none()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum HistoryEvent {
+    Event(Raw<AnySyncTimelineEvent>),
+    UnableToDecrypt(UnableToDecryptEvent),
+}
+
+impl HistoryEvent {
+    pub fn raw(&self) -> &Raw<AnySyncTimelineEvent> {
+        match self {
+            HistoryEvent::Event(event) => event,
+            HistoryEvent::UnableToDecrypt(utd) => &utd.event,
+        }
+    }
+
+    pub fn is_unable_to_decrypt(&self) -> bool {
+        matches!(self, HistoryEvent::UnableToDecrypt(_))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UnableToDecryptEvent {
+    pub event: Raw<AnySyncTimelineEvent>,
+    pub session_id: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct RoomEventContext {
+    pub event: HistoryEvent,
+    // Ordered as returned by the homeserver: closest to the event first.
+    pub events_before: Vec<HistoryEvent>,
+    pub events_after: Vec<HistoryEvent>,
+    pub state: Vec<Raw<AnySyncStateEvent>>,
+    pub start: Option<String>,
+    pub end: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_includes_tokens_and_page_limit() {
+        let request = MessagesRequest::backward().from_token("s1").to_token("s9").limit(250);
+        let params = request.query(0).unwrap();
+
+        assert!(params.contains(&("dir", "b".to_owned())));
+        assert!(params.contains(&("from", "s1".to_owned())));
+        assert!(params.contains(&("to", "s9".to_owned())));
+        assert!(params.contains(&("limit", MAX_PAGE_SIZE.to_string())));
+    }
+
+    #[test]
+    fn query_limits_last_page_to_remaining() {
+        let request = MessagesRequest::forward().limit(150);
+        let params = request.query(120).unwrap();
+
+        assert!(params.contains(&("limit", "30".to_owned())));
+    }
+
+    #[test]
+    fn zero_limit_means_unlimited() {
+        let request = MessagesRequest::forward().limit(0);
+
+        assert_eq!(request.remaining(10), None);
+        assert!(request.query(0).unwrap().iter().all(|(key, _)| *key != "limit"));
+    }
+
+    #[test]
+    fn remaining_saturates() {
+        let request = MessagesRequest::forward().limit(5);
+
+        assert_eq!(request.remaining(3), Some(2));
+        assert_eq!(request.remaining(8), Some(0));
+    }
+
+    #[test]
+    fn advance_stops_on_repeated_token() {
+        let mut request = MessagesRequest::forward();
+
+        assert!(request.advance("t1".to_owned()));
+        assert!(!request.advance("t1".to_owned()));
+        assert!(request.advance("t2".to_owned()));
+    }
+}
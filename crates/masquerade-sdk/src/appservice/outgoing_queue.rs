@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use matrix_sdk::ruma::{OwnedRoomId, OwnedUserId};
+use reqwest::Method;
+use serde_json::Value;
+
+use crate::appservice::ApplicationServiceInner;
+use crate::appservice::http_client::parse_response;
+use crate::appservice::keyed_lock::KeyedLock;
+use crate::appservice::room_database::RoomDatabase;
+use crate::{Error, Result};
+
+const MAX_ATTEMPTS: u32 = 10;
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct OutgoingRequest {
+    pub room_id: OwnedRoomId,
+    pub user_id: OwnedUserId,
+    pub method: Method,
+    pub path: String,
+    pub body: Value,
+}
+
+#[derive(Debug)]
+pub struct OutgoingQueue {
+    appservice: Weak<ApplicationServiceInner>,
+    database: Option<RoomDatabase>,
+    rooms: KeyedLock<OwnedRoomId>,
+}
+
+impl OutgoingQueue {
+    pub fn new(appservice: Weak<ApplicationServiceInner>, database: Option<RoomDatabase>) -> Self {
+        Self { appservice, database, rooms: KeyedLock::new() }
+    }
+
+    pub async fn send(&self, request: OutgoingRequest) -> Result<Value> {
+        let _guard = self.rooms.lock(&request.room_id).await;
+        let id = match &self.database {
+            Some(database) => Some(database.queue_outgoing(&request).await?),
+            None => None,
+        };
+
+        self.execute(id, &request).await
+    }
+
+    pub async fn resume(&self) -> Result<()> {
+        let Some(database) = &self.database else {
+            return Ok(());
+        };
+
+        let mut pending: HashMap<OwnedRoomId, Vec<(i64, OutgoingRequest)>> = HashMap::new();
+        for (id, request) in database.load_outgoing().await? {
+            pending.entry(request.room_id.clone()).or_default().push((id, request));
+        }
+
+        let appservice = self.appservice.upgrade().ok_or(Error::UpgradeError("Appservice not found".to_owned()))?;
+        for (room_id, requests) in pending {
+            tracing::info!("Resuming {} queued requests in room {}", requests.len(), room_id);
+            let guard = self.rooms.lock(&room_id).await;
+            let appservice = Arc::clone(&appservice);
+            tokio::spawn(async move {
+                let _guard = guard;
+                let queue = appservice.outgoing_queue();
+                for (id, request) in requests {
+                    if let Err(error) = queue.execute(Some(id), &request).await {
+                        tracing::warn!("Dropping queued request {} {}: {}", request.method, request.path, error);
+                    }
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    async fn execute(&self, id: Option<i64>, request: &OutgoingRequest) -> Result<Value> {
+        let result = self.execute_with_retries(request).await;
+        if let (Some(id), Some(database)) = (id, &self.database)
+            && let Err(error) = database.remove_outgoing(id).await
+        {
+            tracing::warn!("Unable to remove queued request {} from the database: {}", id, error);
+        }
+
+        result
+    }
+
+    async fn execute_with_retries(&self, request: &OutgoingRequest) -> Result<Value> {
+        let client = self.appservice.upgrade().ok_or(Error::UpgradeError("Appservice not found".to_owned()))?.client();
+
+        let mut attempt = 1;
+        loop {
+            let result = async {
+                let response = client
+                    .request(request.method.clone(), &request.path)
+                    .query(&[("user_id", &request.user_id)])
+                    .json(&request.body)
+                    .send()
+                    .await?;
+                parse_response::<Value>(response).await
+            }
+            .await;
+
+            let delay = match &result {
+                Err(error) if attempt < MAX_ATTEMPTS => match retry_delay(error, attempt) {
+                    Some(delay) => delay,
+                    None => return result,
+                },
+                _ => return result,
+            };
+
+            tracing::warn!(
+                "Request {} {} failed (attempt {}), retrying in {} ms",
+                request.method,
+                request.path,
+                attempt,
+                delay.as_millis()
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+fn retry_delay(error: &Error, attempt: u32) -> Option<Duration> {
+    let backoff = Duration::from_secs(1 << (attempt - 1).min(6)).min(MAX_BACKOFF);
+    match error {
+        Error::UnexpectedStatus(status, _) if error.errcode() == Some("M_LIMIT_EXCEEDED") || status.as_u16() == 429 => {
+            Some(error.retry_after().unwrap_or(backoff))
+        }
+        Error::UnexpectedStatus(status, _) if status.is_server_error() => Some(backoff),
+        Error::ReqwestHttp(error) if error.is_connect() || error.is_timeout() => Some(backoff),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::StatusCode;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn rate_limits_use_server_delay() {
+        let error = Error::UnexpectedStatus(
+            StatusCode::TOO_MANY_REQUESTS,
+            json!({"errcode": "M_LIMIT_EXCEEDED", "retry_after_ms": 1500}),
+        );
+        assert_eq!(retry_delay(&error, 1), Some(Duration::from_millis(1500)));
+
+        let error = Error::UnexpectedStatus(StatusCode::TOO_MANY_REQUESTS, json!({}));
+        assert_eq!(retry_delay(&error, 3), Some(Duration::from_secs(4)));
+    }
+
+    #[test]
+    fn server_errors_back_off_exponentially() {
+        let error = Error::UnexpectedStatus(StatusCode::BAD_GATEWAY, json!({}));
+        assert_eq!(retry_delay(&error, 1), Some(Duration::from_secs(1)));
+        assert_eq!(retry_delay(&error, 2), Some(Duration::from_secs(2)));
+        assert_eq!(retry_delay(&error, 7), Some(MAX_BACKOFF));
+        assert_eq!(retry_delay(&error, 40), Some(MAX_BACKOFF));
+    }
+
+    #[test]
+    fn client_errors_are_not_retried() {
+        let error = Error::UnexpectedStatus(StatusCode::FORBIDDEN, json!({"errcode": "M_FORBIDDEN"}));
+        assert_eq!(retry_delay(&error, 1), None);
+        assert_eq!(retry_delay(&Error::Other("failed".to_owned()), 1), None);
+    }
+}
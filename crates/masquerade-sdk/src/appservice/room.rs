@@ -1,21 +1,73 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::pin::Pin;
 use std::sync::{Arc, Weak};
 
 use async_stream::try_stream;
-use futures::Stream;
-use futures::future::try_join_all;
-use matrix_sdk::ruma::events::AnySyncTimelineEvent;
+use futures::future::{join_all, try_join_all};
+use futures::{Stream, StreamExt, stream};
+use matrix_sdk::ruma::api::client::filter::RoomEventFilter;
+use matrix_sdk::ruma::api::client::space::SpaceHierarchyRoomsChunk;
+use matrix_sdk::ruma::api::client::threads::get_threads::v1::IncludeThreads;
+use matrix_sdk::ruma::events::relation::RelationType;
+use matrix_sdk::ruma::events::room::avatar::{ImageInfo, RoomAvatarEventContent};
+use matrix_sdk::ruma::events::room::canonical_alias::RoomCanonicalAliasEventContent;
+use matrix_sdk::ruma::events::room::create::RoomCreateEventContent;
+use matrix_sdk::ruma::events::room::member::MembershipState;
+use matrix_sdk::ruma::events::room::server_acl::RoomServerAclEventContent;
+use matrix_sdk::ruma::events::room::tombstone::RoomTombstoneEventContent;
+use matrix_sdk::ruma::events::space::child::SpaceChildEventContent;
+use matrix_sdk::ruma::events::space::parent::SpaceParentEventContent;
+use matrix_sdk::ruma::events::{AnySyncTimelineEvent, RoomAccountDataEventType, StateEventContent, StaticEventContent};
+use matrix_sdk::ruma::exports::serde_json::json;
 use matrix_sdk::ruma::serde::Raw;
-use matrix_sdk::ruma::{EventId, OwnedRoomId, OwnedUserId, RoomId, UserId};
-use tokio::sync::RwLock;
+use matrix_sdk::ruma::{
+    EventId,
+    MxcUri,
+    OwnedEventId,
+    OwnedRoomAliasId,
+    OwnedRoomId,
+    OwnedServerName,
+    OwnedUserId,
+    RoomAliasId,
+    RoomId,
+    ServerName,
+    UserId,
+    assign,
+};
+use reqwest::{Method, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{OnceCell, RwLock};
 
 use crate::appservice::ApplicationServiceInner;
 use crate::appservice::handler::ApplicationServiceReference;
-use crate::appservice::http_client::parse_response;
+use crate::appservice::http_client::{encode_path_segment, parse_response};
+use crate::appservice::member::RoomMember;
+use crate::appservice::messages::{
+    HistoryEvent,
+    MessagesPage,
+    MessagesRequest,
+    RoomEventContext,
+    UnableToDecryptEvent,
+};
+use crate::appservice::room_database::RoomDatabase;
 use crate::appservice::user::User;
-use crate::{Error, JoinedMembersResponse, MessagesResponse, Result};
-
+use crate::{
+    ContextResponse,
+    Error,
+    HierarchyResponse,
+    MembersResponse,
+    MessagesResponse,
+    RelationsResponse,
+    Result,
+    SendResponse,
+    ThreadsResponse,
+};
+
+pub(crate) const MAX_CONCURRENT_ROOMS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Direction {
     Forward,
     Backward,
@@ -36,6 +88,13 @@ impl std::fmt::Display for Direction {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct RoomUpgrade {
+    pub predecessor: OwnedRoomId,
+    pub successor: OwnedRoomId,
+    pub body: String,
+}
+
 pub struct Room {
     inner: Arc<RoomKind>,
     appservice: Weak<ApplicationServiceInner>,
@@ -52,12 +111,12 @@ impl ApplicationServiceReference for Room {
 
 impl Room {
     pub async fn from_homeserver(appservice: &Arc<ApplicationServiceInner>, room_id: OwnedRoomId) -> Result<Arc<Self>> {
-        let (is_encrypted, joined_members) = tokio::try_join!(
-            Room::get_encryption(Arc::clone(&appservice), &room_id),
-            Room::get_joined_members(Arc::clone(&appservice), &room_id),
+        let (is_encrypted, members) = tokio::try_join!(
+            Room::get_encryption(Arc::clone(appservice), &room_id),
+            Room::get_members(Arc::clone(appservice), &room_id),
         )?;
 
-        let room_info = RoomInfo { room_id, joined_members: RwLock::new(HashSet::from_iter(joined_members)) };
+        let room_info = RoomInfo::new(room_id, members);
 
         let inner = match is_encrypted {
             true => RoomKind::Encrypted(room_info),
@@ -77,8 +136,11 @@ impl Room {
         Arc::clone(&self.inner)
     }
 
-    pub async fn is_direct(&self) -> bool {
-        self.inner.is_direct().await
+    pub async fn is_direct(&self, mxid: &UserId) -> Result<bool> {
+        match self.appservice()?.user_store().get(mxid).await {
+            Some(user) => Ok(user.is_direct(self.id()).await),
+            None => Ok(false),
+        }
     }
 
     pub async fn is_encrypted(&self) -> bool {
@@ -92,6 +154,48 @@ impl Room {
         self.inner.joined_members().await
     }
 
+    pub async fn members(&self) -> HashMap<OwnedUserId, RoomMember> {
+        self.inner.members().await
+    }
+
+    pub async fn get_member(&self, mxid: &UserId) -> Option<RoomMember> {
+        self.inner.get_member(mxid).await
+    }
+
+    pub async fn predecessor(&self) -> Result<Option<OwnedRoomId>> {
+        let predecessor = self
+            .inner
+            .info()
+            .predecessor
+            .get_or_try_init(async || {
+                let url = format!("/_matrix/client/v3/rooms/{}/state/m.room.create", self.id());
+                let response = self.client()?.get(&url).send().await?;
+                let content: RoomCreateEventContent = parse_response(response).await?;
+
+                Ok::<_, Error>(content.predecessor.map(|previous| previous.room_id))
+            })
+            .await?;
+
+        Ok(predecessor.clone())
+    }
+
+    pub async fn successor(&self) -> Result<Option<OwnedRoomId>> {
+        if let Some(successor) = self.inner.info().successor().await {
+            return Ok(Some(successor));
+        }
+
+        let url = format!("/_matrix/client/v3/rooms/{}/state/m.room.tombstone", self.id());
+        let response = self.client()?.get(&url).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let content: RoomTombstoneEventContent = parse_response(response).await?;
+        self.inner.info().set_successor(content.replacement_room.clone()).await;
+
+        Ok(Some(content.replacement_room))
+    }
+
     pub async fn get_event(&self, event_id: &EventId) -> Result<AnySyncTimelineEvent> {
         let url = format!("/_matrix/client/v3/rooms/{}/event/{}", self.id(), event_id);
         let response = self.client()?.get(url).send().await?;
@@ -99,6 +203,13 @@ impl Room {
         Ok(event)
     }
 
+    pub async fn get_decrypted_event(&self, event_id: &EventId) -> Result<HistoryEvent> {
+        let event = self.get_raw_event(event_id).await?;
+        let users = self.get_appservice_users().await?;
+
+        Ok(self.decrypt_history_event(&users, event).await)
+    }
+
     pub async fn get_raw_event(&self, event_id: &EventId) -> Result<Raw<AnySyncTimelineEvent>> {
         let url = format!("/_matrix/client/v3/rooms/{}/event/{}", self.id(), event_id);
         let response = self.client()?.get(url).send().await?;
@@ -106,62 +217,463 @@ impl Room {
         Ok(event)
     }
 
-    pub async fn get_raw_messages(&self, direction: Direction) -> Result<Vec<Raw<AnySyncTimelineEvent>>> {
-        let url = format!("/_matrix/client/v3/rooms/{}/messages", self.id());
+    pub async fn get_raw_messages(&self, request: impl Into<MessagesRequest>) -> Result<MessagesPage> {
+        let mut request = request.into();
         let mut messages = Vec::new();
-        let mut next_token = None;
 
         loop {
-            let mut params = vec![("dir".to_string(), direction.to_string())];
-            if let Some(token) = next_token {
-                params.push(("from".to_string(), token));
-            }
-
-            let response = self.client()?.get(&url).query(&params).send().await?;
-            let response: MessagesResponse = response.json().await?;
-
-            let chunk = response.chunk.iter().cloned().collect::<Vec<_>>();
-
-            messages.extend(chunk);
+            let response = self.fetch_messages(&request, messages.len()).await?;
+            messages.extend(response.chunk);
 
             match response.end {
-                Some(token) => next_token = Some(token),
-                None => break,
+                Some(token) if request.remaining(messages.len()) != Some(0) => {
+                    if !request.advance(token.clone()) {
+                        return Ok(MessagesPage { chunk: messages, end: Some(token) });
+                    }
+                }
+                end => return Ok(MessagesPage { chunk: messages, end }),
             }
         }
-
-        Ok(messages)
     }
 
     pub fn get_raw_message_stream(
         &self,
-        direction: Direction,
+        request: impl Into<MessagesRequest>,
     ) -> Pin<Box<dyn Stream<Item = Result<Raw<AnySyncTimelineEvent>>> + Send + '_>> {
+        let mut request = request.into();
+
         Box::pin(try_stream! {
-            let url = format!("/_matrix/client/v3/rooms/{}/messages", self.id());
-            let mut next_token = None;
+            let mut fetched = 0;
 
             loop {
-                let mut params = vec![("dir".to_string(), direction.to_string())];
-                if let Some(token) = next_token {
-                    params.push(("from".to_owned(), token));
-                }
-
-                let response = self.client()?.get(&url).query(&params).send().await?;
-                let response: MessagesResponse = response.json().await?;
+                let response = self.fetch_messages(&request, fetched).await?;
+                fetched += response.chunk.len();
 
                 for message in response.chunk.into_iter() {
                     yield message;
                 }
 
                 match response.end {
-                    Some(token) => next_token = Some(token),
-                    None => break,
+                    Some(token) if request.remaining(fetched) != Some(0) => {
+                        if !request.advance(token) {
+                            break;
+                        }
+                    }
+                    _ => break,
+                }
+            }
+        })
+    }
+
+    pub async fn get_messages(&self, request: impl Into<MessagesRequest>) -> Result<MessagesPage<HistoryEvent>> {
+        let request = request.into();
+        let page = self.get_raw_messages(request.clone()).await?;
+
+        let chunk = match request.should_decrypt() {
+            true => {
+                let users = self.get_appservice_users().await?;
+                let mut chunk = Vec::with_capacity(page.chunk.len());
+                for event in page.chunk {
+                    chunk.push(self.decrypt_history_event(&users, event).await);
+                }
+                chunk
+            }
+            false => page.chunk.into_iter().map(HistoryEvent::Event).collect(),
+        };
+
+        Ok(MessagesPage { chunk, end: page.end })
+    }
+
+    pub fn get_message_stream(
+        &self,
+        request: impl Into<MessagesRequest>,
+    ) -> Pin<Box<dyn Stream<Item = Result<HistoryEvent>> + Send + '_>> {
+        let request = request.into();
+
+        Box::pin(try_stream! {
+            let users = match request.should_decrypt() {
+                true => self.get_appservice_users().await?,
+                false => Vec::new(),
+            };
+
+            let decrypt = request.should_decrypt();
+            let mut stream = self.get_raw_message_stream(request);
+            while let Some(event) = stream.next().await {
+                let event = event?;
+                match decrypt {
+                    true => yield self.decrypt_history_event(&users, event).await,
+                    false => yield HistoryEvent::Event(event),
                 }
             }
         })
     }
 
+    pub async fn get_relations(
+        &self,
+        event_id: &EventId,
+        rel_type: Option<RelationType>,
+        event_type: Option<&str>,
+        request: impl Into<MessagesRequest>,
+    ) -> Result<MessagesPage<HistoryEvent>> {
+        let url = match (&rel_type, event_type) {
+            (None, None) => format!("/_matrix/client/v1/rooms/{}/relations/{}", self.id(), event_id),
+            (Some(rel_type), None) => {
+                format!("/_matrix/client/v1/rooms/{}/relations/{}/{}", self.id(), event_id, rel_type)
+            }
+            (Some(rel_type), Some(event_type)) => format!(
+                "/_matrix/client/v1/rooms/{}/relations/{}/{}/{}",
+                self.id(),
+                event_id,
+                rel_type,
+                encode_path_segment(event_type)
+            ),
+            (None, Some(_)) => {
+                return Err(Error::Other("Filtering relations by event type requires a relation type".to_string()));
+            }
+        };
+
+        let mut request = request.into();
+        let mut relations = Vec::new();
+
+        let end = loop {
+            let response = self.client()?.get(&url).query(&request.query(relations.len())?).send().await?;
+            let response: RelationsResponse = parse_response(response).await?;
+            relations.extend(response.chunk);
+
+            match response.next_batch {
+                Some(token) if request.remaining(relations.len()) != Some(0) => {
+                    if !request.advance(token.clone()) {
+                        break Some(token);
+                    }
+                }
+                end => break end,
+            }
+        };
+
+        let users = self.get_appservice_users().await?;
+        let mut chunk = Vec::with_capacity(relations.len());
+        for event in relations {
+            chunk.push(self.decrypt_history_event(&users, event).await);
+        }
+
+        Ok(MessagesPage { chunk, end })
+    }
+
+    pub async fn get_thread(
+        &self,
+        thread_root: &EventId,
+        request: impl Into<MessagesRequest>,
+    ) -> Result<MessagesPage<HistoryEvent>> {
+        self.get_relations(thread_root, Some(RelationType::Thread), None, request).await
+    }
+
+    pub(crate) async fn get_latest_thread_event(&self, thread_root: &EventId) -> Result<Option<OwnedEventId>> {
+        let url = format!("/_matrix/client/v1/rooms/{}/relations/{}/{}", self.id(), thread_root, RelationType::Thread);
+        let params = [("dir", Direction::Backward.to_string()), ("limit", "1".to_string())];
+
+        let response = self.client()?.get(&url).query(&params).send().await?;
+        let response: RelationsResponse = parse_response(response).await?;
+
+        Ok(response.chunk.first().and_then(|event| event.get_field::<OwnedEventId>("event_id").ok().flatten()))
+    }
+
+    pub async fn get_threads(
+        &self,
+        include: IncludeThreads,
+        request: impl Into<MessagesRequest>,
+    ) -> Result<MessagesPage<HistoryEvent>> {
+        let url = format!("/_matrix/client/v1/rooms/{}/threads", self.id());
+        let mut params = vec![("include", include.to_string())];
+        params.extend(request.into().query(0)?.into_iter().filter(|(key, _)| matches!(*key, "from" | "limit")));
+
+        let response = self.client()?.get(&url).query(&params).send().await?;
+        let response: ThreadsResponse = parse_response(response).await?;
+
+        let users = self.get_appservice_users().await?;
+        let mut chunk = Vec::with_capacity(response.chunk.len());
+        for event in response.chunk {
+            chunk.push(self.decrypt_history_event(&users, event).await);
+        }
+
+        Ok(MessagesPage { chunk, end: response.next_batch })
+    }
+
+    pub async fn get_context(
+        &self,
+        event_id: &EventId,
+        limit: Option<usize>,
+        filter: Option<RoomEventFilter>,
+    ) -> Result<RoomEventContext> {
+        let url = format!("/_matrix/client/v3/rooms/{}/context/{}", self.id(), event_id);
+        let mut params = Vec::new();
+        if let Some(limit) = limit {
+            params.push(("limit", limit.to_string()));
+        }
+        if let Some(filter) = &filter {
+            params.push(("filter", serde_json::to_string(filter)?));
+        }
+
+        let response = self.client()?.get(&url).query(&params).send().await?;
+        let response: ContextResponse = parse_response(response).await?;
+
+        let event = match response.event {
+            Some(event) => event,
+            None => self.get_raw_event(event_id).await?,
+        };
+
+        let users = self.get_appservice_users().await?;
+        let event = self.decrypt_history_event(&users, event).await;
+
+        let mut events_before = Vec::with_capacity(response.events_before.len());
+        for event in response.events_before {
+            events_before.push(self.decrypt_history_event(&users, event).await);
+        }
+
+        let mut events_after = Vec::with_capacity(response.events_after.len());
+        for event in response.events_after {
+            events_after.push(self.decrypt_history_event(&users, event).await);
+        }
+
+        Ok(RoomEventContext {
+            event,
+            events_before,
+            events_after,
+            state: response.state,
+            start: response.start,
+            end: response.end,
+        })
+    }
+
+    pub async fn decrypt_event(&self, event: Raw<AnySyncTimelineEvent>) -> Result<HistoryEvent> {
+        let users = self.get_appservice_users().await?;
+        Ok(self.decrypt_history_event(&users, event).await)
+    }
+
+    async fn decrypt_history_event(&self, users: &[Arc<User>], event: Raw<AnySyncTimelineEvent>) -> HistoryEvent {
+        #[derive(Deserialize)]
+        struct EncryptedContent {
+            session_id: Option<String>,
+        }
+
+        if !matches!(event.get_field::<String>("type"), Ok(Some(event_type)) if event_type == "m.room.encrypted") {
+            return HistoryEvent::Event(event);
+        }
+
+        let encrypted = event.clone().cast();
+        for device in join_all(users.iter().map(|user| user.devices())).await.into_iter().flatten() {
+            if !device.encryption().has_room_key(&encrypted, self.id()).await.unwrap_or(false) {
+                continue;
+            }
+
+            match device.encryption().decrypt_event(encrypted.clone(), self.id()).await {
+                Ok(decrypted) => return HistoryEvent::Event(decrypted.event.cast()),
+                Err(error) => tracing::debug!("Device {} failed to decrypt event: {}", device.id(), error),
+            }
+        }
+
+        let session_id = event.get_field::<EncryptedContent>("content").ok().flatten().and_then(|c| c.session_id);
+        HistoryEvent::UnableToDecrypt(UnableToDecryptEvent { event, session_id })
+    }
+
+    async fn fetch_messages(&self, request: &MessagesRequest, fetched: usize) -> Result<MessagesResponse> {
+        let url = format!("/_matrix/client/v3/rooms/{}/messages", self.id());
+        let response = self.client()?.get(&url).query(&request.query(fetched)?).send().await?;
+
+        parse_response(response).await
+    }
+
+    pub async fn set_canonical_alias(
+        &self,
+        sender: &User,
+        alias: Option<&RoomAliasId>,
+        alt_aliases: Vec<OwnedRoomAliasId>,
+    ) -> Result<OwnedEventId> {
+        tracing::info!("Updating canonical alias of room {}", self.id());
+        let content =
+            assign!(RoomCanonicalAliasEventContent::new(), { alias: alias.map(RoomAliasId::to_owned), alt_aliases });
+
+        self.send_state_event(sender, "", content).await
+    }
+
+    pub async fn space_hierarchy(&self) -> Result<Vec<SpaceHierarchyRoomsChunk>> {
+        let mut rooms = Vec::new();
+        let mut from = None;
+
+        loop {
+            let page = self.get_hierarchy_page(from.as_deref(), None, false).await?;
+            rooms.extend(page.chunk);
+
+            match page.end {
+                Some(token) if from.as_ref() != Some(&token) => from = Some(token),
+                _ => break,
+            }
+        }
+
+        Ok(rooms)
+    }
+
+    pub async fn get_hierarchy_page(
+        &self,
+        from: Option<&str>,
+        max_depth: Option<usize>,
+        suggested_only: bool,
+    ) -> Result<MessagesPage<SpaceHierarchyRoomsChunk>> {
+        let url = format!("/_matrix/client/v1/rooms/{}/hierarchy", self.id());
+        let mut params = vec![("suggested_only", suggested_only.to_string())];
+        if let Some(from) = from {
+            params.push(("from", from.to_owned()));
+        }
+        if let Some(max_depth) = max_depth {
+            params.push(("max_depth", max_depth.to_string()));
+        }
+
+        let response = self.client()?.get(&url).query(&params).send().await?;
+        let response: HierarchyResponse = parse_response(response).await?;
+
+        Ok(MessagesPage { chunk: response.rooms, end: response.next_batch })
+    }
+
+    pub async fn add_child(
+        &self,
+        sender: &User,
+        child: &RoomId,
+        via: Vec<OwnedServerName>,
+        suggested: bool,
+        canonical: bool,
+    ) -> Result<()> {
+        tracing::info!("Adding room {} to space {}", child, self.id());
+        let child_content = assign!(SpaceChildEventContent::new(via.clone()), { suggested });
+        self.send_state_event(sender, child.as_str(), child_content).await?;
+
+        let Some(child_room) = self.appservice()?.room_store().get(child).await else {
+            tracing::warn!("Not linking unknown room {} back to space {}", child, self.id());
+            return Ok(());
+        };
+
+        let parent_content = assign!(SpaceParentEventContent::new(via), { canonical });
+        child_room.send_state_event(sender, self.id().as_str(), parent_content).await?;
+
+        Ok(())
+    }
+
+    pub async fn remove_child(&self, sender: &User, child: &RoomId) -> Result<()> {
+        tracing::info!("Removing room {} from space {}", child, self.id());
+
+        // Empty content removes the relation, which the typed contents cannot express.
+        self.send_raw_state_event(sender, "m.space.child", child.as_str(), &json!({})).await?;
+
+        let Some(child_room) = self.appservice()?.room_store().get(child).await else {
+            tracing::warn!("Not unlinking unknown room {} from space {}", child, self.id());
+            return Ok(());
+        };
+
+        child_room.send_raw_state_event(sender, "m.space.parent", self.id().as_str(), &json!({})).await?;
+
+        Ok(())
+    }
+
+    pub async fn for_each_space_room<T, F, Fut>(&self, operation: F) -> Result<Vec<(OwnedRoomId, Result<T>)>>
+    where
+        F: Fn(Arc<Room>) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let appservice = self.appservice()?;
+        let hierarchy = self.space_hierarchy().await?;
+
+        // The hierarchy starts with the space itself, which is not one of its rooms.
+        let futures = hierarchy.into_iter().filter(|chunk| chunk.room_id != *self.id()).map(async |chunk| {
+            let result = match appservice.room_store().get(&chunk.room_id).await {
+                Some(room) => operation(room).await,
+                None => Err(Error::RoomNotFound(chunk.room_id.clone())),
+            };
+
+            (chunk.room_id, result)
+        });
+
+        Ok(stream::iter(futures).buffer_unordered(MAX_CONCURRENT_ROOMS).collect().await)
+    }
+
+    pub async fn get_state_event<C>(&self, state_key: &str) -> Result<Option<C>>
+    where
+        C: StateEventContent + StaticEventContent + DeserializeOwned,
+    {
+        let url = format!(
+            "/_matrix/client/v3/rooms/{}/state/{}/{}",
+            encode_path_segment(self.id().as_str()),
+            C::TYPE,
+            encode_path_segment(state_key)
+        );
+        let response = self.client()?.get(&url).send().await?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            _ => Ok(Some(parse_response(response).await?)),
+        }
+    }
+
+    pub async fn send_state_event<C>(&self, sender: &User, state_key: &str, content: C) -> Result<OwnedEventId>
+    where
+        C: StateEventContent,
+    {
+        let event_type = content.event_type().to_string();
+        self.send_raw_state_event(sender, &event_type, state_key, &content).await
+    }
+
+    async fn send_raw_state_event(
+        &self,
+        sender: &User,
+        event_type: &str,
+        state_key: &str,
+        content: &impl Serialize,
+    ) -> Result<OwnedEventId> {
+        tracing::info!("Sending state event {} to room {} as {}", event_type, self.id(), sender.id());
+
+        let url = format!(
+            "/_matrix/client/v3/rooms/{}/state/{}/{}",
+            encode_path_segment(self.id().as_str()),
+            encode_path_segment(event_type),
+            encode_path_segment(state_key)
+        );
+        let body = serde_json::to_value(content)?;
+
+        let json: SendResponse = serde_json::from_value(sender.queue(self.id(), Method::PUT, url, body).await?)?;
+        Ok(json.event_id)
+    }
+
+    pub async fn set_avatar(
+        &self,
+        sender: &User,
+        avatar_url: &MxcUri,
+        info: Option<ImageInfo>,
+    ) -> Result<OwnedEventId> {
+        tracing::info!("Updating avatar of room {} to {}", self.id(), avatar_url);
+        let content =
+            assign!(RoomAvatarEventContent::new(), { url: Some(avatar_url.to_owned()), info: info.map(Box::new) });
+
+        self.send_state_event(sender, "", content).await
+    }
+
+    pub async fn server_acl(&self) -> Result<Option<RoomServerAclEventContent>> {
+        self.get_state_event("").await
+    }
+
+    pub async fn update_server_acl<F>(&self, sender: &User, update: F) -> Result<OwnedEventId>
+    where
+        F: FnOnce(&mut RoomServerAclEventContent),
+    {
+        let mut acl = self
+            .server_acl()
+            .await?
+            .unwrap_or_else(|| RoomServerAclEventContent::new(true, vec!["*".to_owned()], Vec::new()));
+        update(&mut acl);
+        if let Some(violation) = server_acl_violation(&acl, &self.appservice()?.config().homeserver.server_name) {
+            return Err(Error::InvalidServerAcl(violation));
+        }
+
+        tracing::info!("Updating server ACL of room {}", self.id());
+        self.send_state_event(sender, "", acl).await
+    }
+
     pub async fn get_appservice_users(&self) -> Result<Vec<Arc<User>>> {
         let appservice = self.appservice()?;
 
@@ -186,47 +698,114 @@ impl Room {
         }
     }
 
-    async fn get_joined_members(
+    async fn get_members(
         appservice: Arc<ApplicationServiceInner>,
         room_id: &RoomId,
-    ) -> Result<HashSet<OwnedUserId>> {
-        let url = format!("/_matrix/client/v3/rooms/{}/joined_members", room_id);
+    ) -> Result<HashMap<OwnedUserId, RoomMember>> {
+        let url = format!("/_matrix/client/v3/rooms/{}/members", room_id);
         let response = appservice.client().get(url).send().await?;
-        let json: JoinedMembersResponse = parse_response(response).await?;
-        let members = json.joined.keys().map(OwnedUserId::to_owned).collect::<HashSet<_>>();
-        Ok(members)
+        let json: MembersResponse = parse_response(response).await?;
+        let members = json.chunk.iter().map(|event| (event.state_key.clone(), RoomMember::from(&event.content)));
+        Ok(members.collect())
+    }
+}
+
+// Rooms the homeserver reports as joined are fetched when unknown or when the stored membership disagrees.
+// Stored rooms that still match are kept as they are, since the homeserver redelivers missed transactions.
+fn reconcile_rooms(
+    joined_ids: &HashSet<OwnedRoomId>,
+    known_membership: &HashMap<OwnedRoomId, bool>,
+) -> (Vec<OwnedRoomId>, HashSet<OwnedRoomId>) {
+    let fetch_ids =
+        joined_ids.iter().filter(|room_id| known_membership.get(*room_id) != Some(&true)).cloned().collect();
+    let left_ids = known_membership
+        .iter()
+        .filter(|(room_id, is_joined)| **is_joined && !joined_ids.contains(*room_id))
+        .map(|(room_id, _)| room_id.clone())
+        .collect();
+
+    (fetch_ids, left_ids)
+}
+
+fn server_acl_violation(acl: &RoomServerAclEventContent, own_server: &ServerName) -> Option<String> {
+    let is_valid_glob = |glob: &String| {
+        !glob.is_empty() && glob.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':' | '*' | '?'))
+    };
+
+    if let Some(glob) = acl.allow.iter().chain(&acl.deny).find(|glob| !is_valid_glob(glob)) {
+        return Some(format!("Invalid server name glob: {:?}", glob));
+    }
+
+    if !acl.is_allowed(own_server) {
+        return Some(format!("ACL would deny our own server {}", own_server));
     }
+
+    None
 }
 
 #[derive(Debug)]
 pub struct RoomInfo {
     room_id: OwnedRoomId,
-    joined_members: RwLock<HashSet<OwnedUserId>>,
+    members: RwLock<HashMap<OwnedUserId, RoomMember>>,
+    predecessor: OnceCell<Option<OwnedRoomId>>,
+    successor: RwLock<Option<OwnedRoomId>>,
 }
 
 impl RoomInfo {
+    fn new(room_id: OwnedRoomId, members: HashMap<OwnedUserId, RoomMember>) -> Self {
+        Self { room_id, members: RwLock::new(members), predecessor: OnceCell::new(), successor: RwLock::new(None) }
+    }
+
+    async fn snapshot(&self) -> Self {
+        Self {
+            room_id: self.room_id.clone(),
+            members: RwLock::new(self.members().await),
+            predecessor: self.predecessor.clone(),
+            successor: RwLock::new(self.successor().await),
+        }
+    }
+
     pub fn id(&self) -> &RoomId {
         &self.room_id
     }
 
+    pub async fn successor(&self) -> Option<OwnedRoomId> {
+        self.successor.read().await.clone()
+    }
+
+    pub(crate) async fn is_tombstoned(&self) -> bool {
+        self.successor.read().await.is_some()
+    }
+
+    pub(crate) async fn set_successor(&self, successor: OwnedRoomId) {
+        *self.successor.write().await = Some(successor);
+    }
+
+    pub(crate) fn set_predecessor(&self, predecessor: OwnedRoomId) {
+        if self.predecessor.set(Some(predecessor)).is_err() {
+            tracing::debug!("Predecessor of room {} is already known", self.room_id);
+        }
+    }
+
     pub async fn contains(&self, mxid: &UserId) -> bool {
-        self.joined_members.read().await.contains(mxid)
+        self.members.read().await.get(mxid).is_some_and(RoomMember::is_joined)
     }
 
     pub async fn joined_members(&self) -> HashSet<OwnedUserId> {
-        self.joined_members.read().await.clone()
+        let members = self.members.read().await;
+        members.iter().filter(|(_, member)| member.is_joined()).map(|(mxid, _)| mxid.to_owned()).collect()
     }
 
-    pub async fn is_direct(&self) -> bool {
-        self.joined_members.read().await.len() == 2
+    pub async fn members(&self) -> HashMap<OwnedUserId, RoomMember> {
+        self.members.read().await.clone()
     }
 
-    pub(crate) async fn add_member(&self, joined_member: OwnedUserId) -> bool {
-        self.joined_members.write().await.insert(joined_member)
+    pub async fn get_member(&self, mxid: &UserId) -> Option<RoomMember> {
+        self.members.read().await.get(mxid).cloned()
     }
 
-    pub(crate) async fn remove_member(&self, left_member: &UserId) -> bool {
-        self.joined_members.write().await.remove(left_member)
+    pub(crate) async fn set_member(&self, mxid: OwnedUserId, member: RoomMember) -> Option<RoomMember> {
+        self.members.write().await.insert(mxid, member)
     }
 }
 
@@ -237,12 +816,12 @@ pub enum RoomKind {
 }
 
 impl RoomKind {
-    pub fn new_encrypted(room_id: OwnedRoomId, joined_members: impl Into<HashSet<OwnedUserId>>) -> Arc<Self> {
-        Arc::new(RoomKind::Encrypted(RoomInfo { room_id, joined_members: RwLock::new(joined_members.into()) }))
+    pub fn new_encrypted(room_id: OwnedRoomId, members: HashMap<OwnedUserId, RoomMember>) -> Arc<Self> {
+        Arc::new(RoomKind::Encrypted(RoomInfo::new(room_id, members)))
     }
 
-    pub fn new_unencrypted(room_id: OwnedRoomId, joined_members: impl Into<HashSet<OwnedUserId>>) -> Arc<Self> {
-        Arc::new(RoomKind::Unencrypted(RoomInfo { room_id, joined_members: RwLock::new(joined_members.into()) }))
+    pub fn new_unencrypted(room_id: OwnedRoomId, members: HashMap<OwnedUserId, RoomMember>) -> Arc<Self> {
+        Arc::new(RoomKind::Unencrypted(RoomInfo::new(room_id, members)))
     }
 
     fn upgrade(self: &Arc<Self>, appservice: Weak<ApplicationServiceInner>) -> Arc<Room> {
@@ -253,30 +832,28 @@ impl RoomKind {
         matches!(self, RoomKind::Encrypted(_))
     }
 
-    pub async fn is_direct(&self) -> bool {
+    pub async fn joined_members(&self) -> HashSet<OwnedUserId> {
         match self {
-            RoomKind::Encrypted(room_info) | RoomKind::Unencrypted(room_info) => room_info.is_direct().await,
+            RoomKind::Encrypted(room_info) | RoomKind::Unencrypted(room_info) => room_info.joined_members().await,
         }
     }
 
-    pub async fn joined_members(&self) -> HashSet<OwnedUserId> {
+    pub async fn members(&self) -> HashMap<OwnedUserId, RoomMember> {
         match self {
-            RoomKind::Encrypted(room_info) | RoomKind::Unencrypted(room_info) => room_info.joined_members().await,
+            RoomKind::Encrypted(room_info) | RoomKind::Unencrypted(room_info) => room_info.members().await,
         }
     }
 
-    pub async fn add_member(&self, joined_member: OwnedUserId) -> bool {
+    pub async fn get_member(&self, mxid: &UserId) -> Option<RoomMember> {
         match self {
-            RoomKind::Encrypted(room_info) | RoomKind::Unencrypted(room_info) => {
-                room_info.add_member(joined_member).await
-            }
+            RoomKind::Encrypted(room_info) | RoomKind::Unencrypted(room_info) => room_info.get_member(mxid).await,
         }
     }
 
-    pub async fn remove_member(&self, left_member: &UserId) -> bool {
+    pub(crate) async fn set_member(&self, mxid: OwnedUserId, member: RoomMember) -> Option<RoomMember> {
         match self {
             RoomKind::Encrypted(room_info) | RoomKind::Unencrypted(room_info) => {
-                room_info.remove_member(left_member).await
+                room_info.set_member(mxid, member).await
             }
         }
     }
@@ -293,12 +870,21 @@ impl RoomKind {
             RoomKind::Encrypted(room_info) | RoomKind::Unencrypted(room_info) => room_info.id(),
         }
     }
+
+    pub fn info(&self) -> &RoomInfo {
+        match self {
+            RoomKind::Encrypted(room_info) | RoomKind::Unencrypted(room_info) => room_info,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct RoomStore {
     appservice: Weak<ApplicationServiceInner>,
     rooms: RwLock<HashMap<OwnedRoomId, Arc<RoomKind>>>,
+    user_rooms: RwLock<HashMap<OwnedUserId, HashSet<OwnedRoomId>>>,
+    carried_account_data: RwLock<BTreeSet<String>>,
+    database: RoomDatabase,
 }
 
 impl ApplicationServiceReference for RoomStore {
@@ -311,8 +897,40 @@ impl ApplicationServiceReference for RoomStore {
 }
 
 impl RoomStore {
-    pub fn new(appservice: Weak<ApplicationServiceInner>) -> Self {
-        Self { appservice, rooms: RwLock::new(HashMap::new()) }
+    pub fn new(appservice: Weak<ApplicationServiceInner>, database: RoomDatabase) -> Self {
+        Self {
+            appservice,
+            rooms: RwLock::new(HashMap::new()),
+            user_rooms: RwLock::new(HashMap::new()),
+            carried_account_data: RwLock::new(BTreeSet::from([RoomAccountDataEventType::Tag.to_string()])),
+            database,
+        }
+    }
+
+    pub(crate) async fn load(&self) -> Result<()> {
+        let stored_rooms = self.database.load_rooms().await?;
+        tracing::info!("Loaded {} rooms from the room database", stored_rooms.len());
+
+        for stored in stored_rooms {
+            let room = match stored.encrypted {
+                true => RoomKind::new_encrypted(stored.room_id.clone(), stored.members),
+                false => RoomKind::new_unencrypted(stored.room_id.clone(), stored.members),
+            };
+
+            let inserted = match self.rooms.write().await.entry(stored.room_id) {
+                Entry::Vacant(entry) => {
+                    entry.insert(Arc::clone(&room));
+                    true
+                }
+                Entry::Occupied(_) => false,
+            };
+
+            if inserted {
+                self.index_room(&room).await;
+            }
+        }
+
+        Ok(())
     }
 
     pub async fn get(&self, room_id: &RoomId) -> Option<Arc<Room>> {
@@ -323,15 +941,20 @@ impl RoomStore {
         }
     }
 
+    pub(crate) async fn get_user_rooms(&self, mxid: &UserId) -> HashSet<OwnedRoomId> {
+        self.user_rooms.read().await.get(mxid).cloned().unwrap_or_default()
+    }
+
     pub(crate) async fn get_encrypted_members(&self, user: &Arc<User>) -> HashSet<OwnedUserId> {
+        let room_ids = self.get_user_rooms(user.id()).await;
         let rooms = self.rooms.read().await;
         let mut accumulator = HashSet::from_iter([user.id().to_owned()]);
 
-        for room in rooms.values() {
-            if let RoomKind::Encrypted(room_info) = room.as_ref() {
-                if room_info.contains(user.id()).await {
-                    accumulator.extend(room_info.joined_members().await);
-                }
+        for room_id in room_ids {
+            if let Some(room) = rooms.get(&room_id)
+                && room.is_encrypted()
+            {
+                accumulator.extend(room.joined_members().await);
             }
         }
 
@@ -339,7 +962,7 @@ impl RoomStore {
     }
 
     pub(crate) async fn upgrade_room_encryption(&self, room_id: &RoomId) -> Result<()> {
-        let joined_members = {
+        let room_info = {
             let rooms = self.rooms.read().await;
             let Some(room) = rooms.get(room_id) else {
                 return Ok(());
@@ -348,80 +971,259 @@ impl RoomStore {
             let RoomKind::Unencrypted(room_info) = room.as_ref() else {
                 return Ok(());
             };
+            if room_info.is_tombstoned().await {
+                return Ok(());
+            }
 
-            room_info.joined_members().await
+            room_info.snapshot().await
         };
 
-        let new_room = RoomKind::new_encrypted(room_id.to_owned(), joined_members);
+        let new_room = Arc::new(RoomKind::Encrypted(room_info));
         self.rooms.write().await.insert(room_id.to_owned(), new_room.clone());
+        self.database.set_encrypted(room_id).await?;
 
-        Ok(self.update_tracked_users(&new_room).await?)
+        self.track_room_members(&new_room).await
     }
 
-    pub(crate) async fn populate_known_rooms(&self, rooms: &[OwnedRoomId]) -> Result<()> {
-        let mut known_rooms = self.rooms.write().await;
-        let known_ids: HashSet<OwnedRoomId> = HashSet::from_iter(known_rooms.keys().cloned());
-        let new_ids: HashSet<OwnedRoomId> = HashSet::from_iter(rooms.iter().cloned());
+    pub(crate) async fn populate_known_rooms(&self, user: &User, rooms: &[OwnedRoomId]) -> Result<()> {
+        let appservice = self.appservice()?;
+        let appservice_users = appservice.user_store().keys().await;
+        let joined_ids: HashSet<OwnedRoomId> = HashSet::from_iter(rooms.iter().cloned());
+
+        let known_rooms: Vec<Arc<RoomKind>> = self.rooms.read().await.values().cloned().collect();
+        let mut known_membership = HashMap::new();
+        for room in &known_rooms {
+            let is_joined = room.get_member(user.id()).await.as_ref().is_some_and(RoomMember::is_joined);
+            known_membership.insert(room.id().to_owned(), is_joined);
+        }
+
+        let (fetch_ids, left_ids) = reconcile_rooms(&joined_ids, &known_membership);
+        for room in known_rooms.into_iter().filter(|room| left_ids.contains(room.id())) {
+            tracing::info!("User {} left room {} while offline", user.id(), room.id());
+            let left = RoomMember { membership: MembershipState::Leave, displayname: None, avatar_url: None };
+            room.set_member(user.id().to_owned(), left.clone()).await;
+            self.index_member(room.id(), user.id(), false).await;
+            self.database.set_member(room.id(), user.id(), &left).await?;
+
+            if room.joined_members().await.is_disjoint(&appservice_users) {
+                self.unindex_room(&room).await;
+                self.rooms.write().await.remove(room.id());
+                self.database.remove_room(room.id()).await?;
+            }
+        }
 
-        for room_id in new_ids.difference(&known_ids) {
-            let room = self.appservice()?.create_room(room_id.to_owned()).await?;
-            known_rooms.insert(room_id.to_owned(), Arc::clone(&room.inner));
+        tracing::info!("Fetching {} of {} rooms of {} from the homeserver", fetch_ids.len(), rooms.len(), user.id());
+
+        for room_id in fetch_ids {
+            let room = appservice.create_room(room_id.to_owned()).await?;
+            self.persist(&room.inner).await?;
+            self.index_room(&room.inner).await;
+
+            let previous = self.rooms.write().await.insert(room_id, Arc::clone(&room.inner));
+            if let Some(previous) = previous {
+                self.unindex_stale(&previous, &room.inner).await;
+            }
+            if room.inner.is_encrypted() {
+                self.track_room_members(&room.inner).await?;
+            }
         }
 
         Ok(())
     }
 
-    pub async fn add_room_member(&self, room_id: &RoomId, mxid: OwnedUserId) -> Result<()> {
-        let room = {
+    pub(crate) async fn update_member(
+        &self,
+        room_id: &RoomId,
+        mxid: &UserId,
+        member: RoomMember,
+    ) -> Result<Option<RoomMember>> {
+        let is_joined = member.is_joined();
+        let (room, previous) = {
             let mut rooms = self.rooms.write().await;
             match rooms.get(room_id) {
                 Some(room) => {
-                    room.add_member(mxid).await;
-                    Arc::clone(room)
+                    if !room.info().is_tombstoned().await {
+                        self.database.set_member(room_id, mxid, &member).await?;
+                        self.index_member(room_id, mxid, is_joined).await;
+                    }
+                    (Arc::clone(room), room.set_member(mxid.to_owned(), member).await)
                 }
-                None => {
+                None if is_joined => {
                     let room = self.appservice()?.create_room(room_id.to_owned()).await?;
+                    self.persist(&room.inner).await?;
+                    self.index_room(&room.inner).await;
                     rooms.insert(room_id.to_owned(), Arc::clone(&room.inner));
-                    Arc::clone(&room.inner)
+                    (Arc::clone(&room.inner), None)
                 }
+                None => return Ok(None),
             }
         };
 
-        if !room.is_encrypted() {
-            return Ok(());
+        // The olm machine cannot stop tracking users, so only newly joined members produce a delta.
+        let was_joined = previous.as_ref().is_some_and(RoomMember::is_joined);
+        if room.is_encrypted() && is_joined && !was_joined && !room.info().is_tombstoned().await {
+            self.track_new_member(&room, mxid).await?;
         }
 
-        Ok(self.update_tracked_users(&room).await?)
+        Ok(previous)
     }
 
-    pub async fn remove_room_member(&self, room_id: &RoomId, mxid: &UserId) -> Result<()> {
-        let room = {
-            let rooms = self.rooms.write().await;
-            match rooms.get(room_id) {
-                Some(room) => {
-                    room.remove_member(mxid).await;
-                    if !room.is_encrypted() {
-                        return Ok(());
-                    }
-
-                    Arc::clone(room)
-                }
-                None => return Ok(()),
+    pub(crate) async fn link_upgrade(&self, predecessor: &RoomId, successor: &RoomId) -> Result<Arc<Room>> {
+        let old_room = self.get_kind(predecessor).await.ok_or(Error::RoomNotFound(predecessor.to_owned()))?;
+        old_room.info().set_successor(successor.to_owned()).await;
+
+        // The predecessor stays reachable for `Room::successor`, but its members are no longer indexed or tracked.
+        self.unindex_room(&old_room).await;
+        self.database.remove_room(predecessor).await?;
+
+        let room = match self.get_kind(successor).await {
+            Some(room) => room,
+            None => {
+                let room = self.appservice()?.create_room(successor.to_owned()).await?;
+                self.persist(&room.inner).await?;
+                self.index_room(&room.inner).await;
+                let mut rooms = self.rooms.write().await;
+                Arc::clone(rooms.entry(successor.to_owned()).or_insert_with(|| Arc::clone(&room.inner)))
             }
         };
 
-        Ok(self.update_tracked_users(&room).await?)
+        room.info().set_predecessor(predecessor.to_owned());
+        if room.is_encrypted() {
+            self.track_room_members(&room).await?;
+        }
+
+        Ok(room.upgrade(Weak::clone(&self.appservice)))
+    }
+
+    pub(crate) async fn carry_account_data(&self, event_type: &str) {
+        self.carried_account_data.write().await.insert(event_type.to_owned());
+    }
+
+    pub(crate) async fn carried_account_data(&self) -> BTreeSet<String> {
+        self.carried_account_data.read().await.clone()
+    }
+
+    async fn get_kind(&self, room_id: &RoomId) -> Option<Arc<RoomKind>> {
+        self.rooms.read().await.get(room_id).cloned()
     }
 
-    async fn update_tracked_users(&self, room: &Arc<RoomKind>) -> Result<()> {
+    async fn persist(&self, room: &RoomKind) -> Result<()> {
+        self.database.save_room(room.id(), room.is_encrypted(), room.members().await).await
+    }
+
+    async fn index_room(&self, room: &RoomKind) {
+        let mut user_rooms = self.user_rooms.write().await;
+        for mxid in room.joined_members().await {
+            user_rooms.entry(mxid).or_default().insert(room.id().to_owned());
+        }
+    }
+
+    async fn unindex_room(&self, room: &RoomKind) {
+        for mxid in room.joined_members().await {
+            self.index_member(room.id(), &mxid, false).await;
+        }
+    }
+
+    async fn unindex_stale(&self, previous: &RoomKind, current: &RoomKind) {
+        let current_members = current.joined_members().await;
+        for mxid in previous.joined_members().await.difference(&current_members) {
+            self.index_member(previous.id(), mxid, false).await;
+        }
+    }
+
+    async fn index_member(&self, room_id: &RoomId, mxid: &UserId, is_joined: bool) {
+        let mut user_rooms = self.user_rooms.write().await;
+        if is_joined {
+            user_rooms.entry(mxid.to_owned()).or_default().insert(room_id.to_owned());
+        } else if let Some(rooms) = user_rooms.get_mut(mxid) {
+            rooms.remove(room_id);
+            if rooms.is_empty() {
+                user_rooms.remove(mxid);
+            }
+        }
+    }
+
+    async fn track_room_members(&self, room: &Arc<RoomKind>) -> Result<()> {
+        let full_room = room.upgrade(Weak::clone(&self.appservice));
+        let members = room.joined_members().await;
+
+        for user in full_room.get_appservice_users().await? {
+            user.update_tracked_users(&members).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn track_new_member(&self, room: &Arc<RoomKind>, mxid: &UserId) -> Result<()> {
         let full_room = room.upgrade(Weak::clone(&self.appservice));
-        let users = full_room.get_appservice_users().await?;
+        let delta = HashSet::from_iter([mxid.to_owned()]);
 
-        for user in users {
-            let encrypted_members = self.get_encrypted_members(&user).await;
-            user.update_tracked_users(&encrypted_members).await?;
+        for user in full_room.get_appservice_users().await? {
+            match user.id() == mxid {
+                true => user.update_tracked_users(&room.joined_members().await).await?,
+                false => user.update_tracked_users(&delta).await?,
+            }
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::{room_id, server_name};
+
+    use super::*;
+
+    #[test]
+    fn reconcile_keeps_rooms_that_match() {
+        let joined_ids = HashSet::from([
+            room_id!("!kept:example.org").to_owned(),
+            room_id!("!stale:example.org").to_owned(),
+            room_id!("!missing:example.org").to_owned(),
+        ]);
+        let known_membership = HashMap::from([
+            (room_id!("!kept:example.org").to_owned(), true),
+            (room_id!("!stale:example.org").to_owned(), false),
+            (room_id!("!left:example.org").to_owned(), true),
+            (room_id!("!old:example.org").to_owned(), false),
+        ]);
+
+        let (mut fetch_ids, left_ids) = reconcile_rooms(&joined_ids, &known_membership);
+        fetch_ids.sort();
+        assert_eq!(fetch_ids, [room_id!("!missing:example.org"), room_id!("!stale:example.org")]);
+        assert_eq!(left_ids, HashSet::from([room_id!("!left:example.org").to_owned()]));
+    }
+
+    fn acl(allow: &[&str], deny: &[&str]) -> RoomServerAclEventContent {
+        RoomServerAclEventContent::new(
+            false,
+            allow.iter().map(|glob| glob.to_string()).collect(),
+            deny.iter().map(|glob| glob.to_string()).collect(),
+        )
+    }
+
+    #[test]
+    fn accepts_acl_allowing_own_server() {
+        let own_server = server_name!("example.org");
+        assert_eq!(server_acl_violation(&acl(&["*"], &["evil.com", "*.evil.com"]), own_server), None);
+        assert_eq!(server_acl_violation(&acl(&["example.org", "matrix.org"], &[]), own_server), None);
+        assert_eq!(server_acl_violation(&acl(&["ex?mple.org"], &[]), server_name!("example.org:8448")), None);
+    }
+
+    #[test]
+    fn rejects_acl_denying_own_server() {
+        let own_server = server_name!("example.org");
+        assert!(server_acl_violation(&acl(&["*"], &["example.*"]), own_server).is_some());
+        assert!(server_acl_violation(&acl(&["matrix.org"], &[]), own_server).is_some());
+        assert!(server_acl_violation(&acl(&[], &[]), own_server).is_some());
+    }
+
+    #[test]
+    fn rejects_invalid_globs() {
+        let own_server = server_name!("example.org");
+        assert!(server_acl_violation(&acl(&["*", ""], &[]), own_server).is_some());
+        assert!(server_acl_violation(&acl(&["*"], &["evil com"]), own_server).is_some());
+        assert!(server_acl_violation(&acl(&["*"], &["evil.com/"]), own_server).is_some());
+    }
+}
@@ -0,0 +1,362 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use matrix_sdk::ruma::events::room::member::MembershipState;
+use matrix_sdk::ruma::{OwnedMxcUri, OwnedRoomId, OwnedUserId, RoomId, UserId};
+use reqwest::Method;
+use rusqlite::{Connection, OptionalExtension, params};
+
+use crate::Result;
+use crate::appservice::member::RoomMember;
+use crate::appservice::outgoing_queue::OutgoingRequest;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS rooms (
+        room_id TEXT PRIMARY KEY NOT NULL,
+        encrypted INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS members (
+        room_id TEXT NOT NULL REFERENCES rooms (room_id) ON DELETE CASCADE,
+        user_id TEXT NOT NULL,
+        membership TEXT NOT NULL,
+        displayname TEXT,
+        avatar_url TEXT,
+        PRIMARY KEY (room_id, user_id)
+    );
+    CREATE TABLE IF NOT EXISTS outgoing (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        room_id TEXT NOT NULL,
+        user_id TEXT NOT NULL,
+        method TEXT NOT NULL,
+        path TEXT NOT NULL,
+        body TEXT NOT NULL
+    );
+";
+
+#[derive(Debug)]
+pub struct StoredRoom {
+    pub room_id: OwnedRoomId,
+    pub encrypted: bool,
+    pub members: HashMap<OwnedUserId, RoomMember>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RoomDatabase {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl RoomDatabase {
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_owned();
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let connection = tokio::task::spawn_blocking(move || {
+            let connection = Connection::open(path)?;
+            connection.pragma_update(None, "foreign_keys", true)?;
+            connection.pragma_update(None, "journal_mode", "WAL")?;
+            connection.execute_batch(SCHEMA)?;
+
+            Ok::<_, rusqlite::Error>(connection)
+        })
+        .await??;
+
+        Ok(Self { connection: Arc::new(Mutex::new(connection)) })
+    }
+
+    pub async fn load_rooms(&self) -> Result<Vec<StoredRoom>> {
+        self.with_connection(|connection| {
+            let mut rooms = HashMap::new();
+            let mut statement = connection.prepare("SELECT room_id, encrypted FROM rooms")?;
+            for row in statement.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, bool>(1)?)))? {
+                let (room_id, encrypted) = row?;
+                match RoomId::parse(&room_id) {
+                    Ok(parsed) => {
+                        rooms.insert(room_id, StoredRoom { room_id: parsed, encrypted, members: HashMap::new() });
+                    }
+                    Err(error) => tracing::warn!("Skipping stored room with invalid id {}: {}", room_id, error),
+                }
+            }
+
+            let mut statement =
+                connection.prepare("SELECT room_id, user_id, membership, displayname, avatar_url FROM members")?;
+            let rows = statement.query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                    row.get::<_, Option<String>>(4)?,
+                ))
+            })?;
+
+            for row in rows {
+                let (room_id, user_id, membership, displayname, avatar_url) = row?;
+                let Some(room) = rooms.get_mut(&room_id) else {
+                    continue;
+                };
+                let Ok(user_id) = UserId::parse(&user_id) else {
+                    tracing::warn!("Skipping stored member with invalid id {} in room {}", user_id, room_id);
+                    continue;
+                };
+
+                let member = RoomMember {
+                    membership: MembershipState::from(membership),
+                    displayname,
+                    avatar_url: avatar_url.map(OwnedMxcUri::from),
+                };
+                room.members.insert(user_id, member);
+            }
+
+            Ok(rooms.into_values().collect())
+        })
+        .await
+    }
+
+    pub async fn save_room(
+        &self,
+        room_id: &RoomId,
+        encrypted: bool,
+        members: HashMap<OwnedUserId, RoomMember>,
+    ) -> Result<()> {
+        let room_id = room_id.to_owned();
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute("DELETE FROM rooms WHERE room_id = ?1", params![room_id.as_str()])?;
+            transaction.execute(
+                "INSERT INTO rooms (room_id, encrypted) VALUES (?1, ?2)",
+                params![room_id.as_str(), encrypted],
+            )?;
+
+            for (user_id, member) in &members {
+                insert_member(&transaction, &room_id, user_id, member)?;
+            }
+
+            transaction.commit()
+        })
+        .await
+    }
+
+    pub async fn set_encrypted(&self, room_id: &RoomId) -> Result<()> {
+        let room_id = room_id.to_owned();
+        self.with_connection(move |connection| {
+            connection.execute("UPDATE rooms SET encrypted = 1 WHERE room_id = ?1", params![room_id.as_str()])?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn set_member(&self, room_id: &RoomId, user_id: &UserId, member: &RoomMember) -> Result<()> {
+        let (room_id, user_id, member) = (room_id.to_owned(), user_id.to_owned(), member.clone());
+        self.with_connection(move |connection| {
+            let known = connection
+                .query_row("SELECT 1 FROM rooms WHERE room_id = ?1", params![room_id.as_str()], |_| Ok(()))
+                .optional()?;
+
+            if known.is_some() {
+                insert_member(connection, &room_id, &user_id, &member)?;
+            }
+
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn remove_room(&self, room_id: &RoomId) -> Result<()> {
+        let room_id = room_id.to_owned();
+        self.with_connection(move |connection| {
+            connection.execute("DELETE FROM rooms WHERE room_id = ?1", params![room_id.as_str()])?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn queue_outgoing(&self, request: &OutgoingRequest) -> Result<i64> {
+        let body = serde_json::to_string(&request.body)?;
+        let (room_id, user_id, method, path) = (
+            request.room_id.to_string(),
+            request.user_id.to_string(),
+            request.method.to_string(),
+            request.path.clone(),
+        );
+        self.with_connection(move |connection| {
+            connection.execute(
+                "INSERT INTO outgoing (room_id, user_id, method, path, body) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![room_id, user_id, method, path, body],
+            )?;
+            Ok(connection.last_insert_rowid())
+        })
+        .await
+    }
+
+    pub async fn remove_outgoing(&self, id: i64) -> Result<()> {
+        self.with_connection(move |connection| {
+            connection.execute("DELETE FROM outgoing WHERE id = ?1", params![id])?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn load_outgoing(&self) -> Result<Vec<(i64, OutgoingRequest)>> {
+        self.with_connection(|connection| {
+            let mut statement =
+                connection.prepare("SELECT id, room_id, user_id, method, path, body FROM outgoing ORDER BY id")?;
+            let rows = statement.query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, String>(4)?,
+                    row.get::<_, String>(5)?,
+                ))
+            })?;
+
+            let mut requests = Vec::new();
+            for row in rows {
+                let (id, room_id, user_id, method, path, body) = row?;
+                let parsed = (
+                    RoomId::parse(&room_id).ok(),
+                    UserId::parse(&user_id).ok(),
+                    Method::from_bytes(method.as_bytes()).ok(),
+                    serde_json::from_str(&body).ok(),
+                );
+                let (Some(room_id), Some(user_id), Some(method), Some(body)) = parsed else {
+                    tracing::warn!("Skipping invalid queued request {} for room {}", id, room_id);
+                    continue;
+                };
+
+                requests.push((id, OutgoingRequest { room_id, user_id, method, path, body }));
+            }
+
+            Ok(requests)
+        })
+        .await
+    }
+
+    async fn with_connection<T, F>(&self, operation: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let connection = Arc::clone(&self.connection);
+        let result = tokio::task::spawn_blocking(move || {
+            let mut connection = connection.lock().unwrap_or_else(PoisonError::into_inner);
+            operation(&mut connection)
+        })
+        .await??;
+
+        Ok(result)
+    }
+}
+
+fn insert_member(
+    connection: &Connection,
+    room_id: &RoomId,
+    user_id: &UserId,
+    member: &RoomMember,
+) -> rusqlite::Result<()> {
+    connection.execute(
+        "INSERT OR REPLACE INTO members (room_id, user_id, membership, displayname, avatar_url)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            room_id.as_str(),
+            user_id.as_str(),
+            member.membership.as_str(),
+            member.displayname,
+            member.avatar_url.as_ref().map(|url| url.as_str()),
+        ],
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::{mxc_uri, room_id, user_id};
+
+    use super::*;
+
+    fn member(membership: MembershipState, displayname: Option<&str>) -> RoomMember {
+        RoomMember { membership, displayname: displayname.map(str::to_owned), avatar_url: None }
+    }
+
+    #[tokio::test]
+    async fn rooms_round_trip() {
+        let database = RoomDatabase::open(":memory:").await.unwrap();
+        let alice = RoomMember {
+            membership: MembershipState::Join,
+            displayname: Some("Alice".to_owned()),
+            avatar_url: Some(mxc_uri!("mxc://example.org/alice").to_owned()),
+        };
+        let members = HashMap::from([
+            (user_id!("@alice:example.org").to_owned(), alice.clone()),
+            (user_id!("@bob:example.org").to_owned(), member(MembershipState::Invite, None)),
+        ]);
+        database.save_room(room_id!("!room:example.org"), false, members).await.unwrap();
+        database.set_encrypted(room_id!("!room:example.org")).await.unwrap();
+
+        let rooms = database.load_rooms().await.unwrap();
+        assert_eq!(rooms.len(), 1);
+        assert_eq!(rooms[0].room_id, room_id!("!room:example.org"));
+        assert!(rooms[0].encrypted);
+        assert_eq!(rooms[0].members.len(), 2);
+        assert_eq!(rooms[0].members[user_id!("@alice:example.org")], alice);
+        assert_eq!(rooms[0].members[user_id!("@bob:example.org")].membership, MembershipState::Invite);
+    }
+
+    #[tokio::test]
+    async fn set_member_updates_known_rooms_only() {
+        let database = RoomDatabase::open(":memory:").await.unwrap();
+        database.save_room(room_id!("!room:example.org"), false, HashMap::new()).await.unwrap();
+
+        let left = member(MembershipState::Leave, Some("Alice"));
+        database.set_member(room_id!("!room:example.org"), user_id!("@alice:example.org"), &left).await.unwrap();
+        database.set_member(room_id!("!other:example.org"), user_id!("@alice:example.org"), &left).await.unwrap();
+
+        let rooms = database.load_rooms().await.unwrap();
+        assert_eq!(rooms.len(), 1);
+        assert_eq!(rooms[0].members[user_id!("@alice:example.org")], left);
+    }
+
+    #[tokio::test]
+    async fn outgoing_requests_round_trip() {
+        let database = RoomDatabase::open(":memory:").await.unwrap();
+        let request = |path: &str| OutgoingRequest {
+            room_id: room_id!("!room:example.org").to_owned(),
+            user_id: user_id!("@alice:example.org").to_owned(),
+            method: Method::PUT,
+            path: path.to_owned(),
+            body: serde_json::json!({"body": path}),
+        };
+
+        let first = database.queue_outgoing(&request("/first")).await.unwrap();
+        let second = database.queue_outgoing(&request("/second")).await.unwrap();
+        database.queue_outgoing(&request("/third")).await.unwrap();
+        database.remove_outgoing(second).await.unwrap();
+
+        let pending = database.load_outgoing().await.unwrap();
+        let paths: Vec<_> = pending.iter().map(|(_, request)| request.path.as_str()).collect();
+        assert_eq!(paths, ["/first", "/third"]);
+        assert_eq!(pending[0].0, first);
+        assert_eq!(pending[0].1.method, Method::PUT);
+        assert_eq!(pending[0].1.user_id, user_id!("@alice:example.org"));
+        assert_eq!(pending[0].1.body, serde_json::json!({"body": "/first"}));
+    }
+
+    #[tokio::test]
+    async fn remove_room_removes_members() {
+        let database = RoomDatabase::open(":memory:").await.unwrap();
+        let members = HashMap::from([(user_id!("@alice:example.org").to_owned(), member(MembershipState::Join, None))]);
+        database.save_room(room_id!("!room:example.org"), true, members).await.unwrap();
+        database.remove_room(room_id!("!room:example.org")).await.unwrap();
+        assert!(database.load_rooms().await.unwrap().is_empty());
+
+        database.save_room(room_id!("!room:example.org"), false, HashMap::new()).await.unwrap();
+        let rooms = database.load_rooms().await.unwrap();
+        assert!(rooms[0].members.is_empty());
+        assert!(!rooms[0].encrypted);
+    }
+}
@@ -3,11 +3,23 @@ use std::net::IpAddr;
 
 use matrix_sdk::ServerName;
 use matrix_sdk::ruma::api::client::device::Device;
+use matrix_sdk::ruma::api::client::space::SpaceHierarchyRoomsChunk;
 use matrix_sdk::ruma::api::client::sync::sync_events::DeviceLists;
-use matrix_sdk::ruma::events::{AnySyncEphemeralRoomEvent, AnySyncTimelineEvent, AnyToDeviceEvent};
+use matrix_sdk::ruma::events::room::member::RoomMemberEventContent;
+use matrix_sdk::ruma::events::{AnySyncEphemeralRoomEvent, AnySyncStateEvent, AnySyncTimelineEvent, AnyToDeviceEvent};
 use matrix_sdk::ruma::presence::PresenceState;
 use matrix_sdk::ruma::serde::Raw;
-use matrix_sdk::ruma::{OneTimeKeyAlgorithm, OwnedEventId, OwnedRoomId, OwnedUserId, UInt};
+use matrix_sdk::ruma::{
+    OneTimeKeyAlgorithm,
+    OwnedDeviceId,
+    OwnedEventId,
+    OwnedMxcUri,
+    OwnedRoomId,
+    OwnedServerName,
+    OwnedUserId,
+    RoomId,
+    UInt,
+};
 use serde::{Deserialize, Serialize};
 use url::Url;
 
@@ -36,12 +48,18 @@ pub struct Appservice {
     pub displayname: String,
     pub as_token: String,
     pub hs_token: String,
+    #[serde(default)]
+    pub user_namespaces: Vec<String>,
+    #[serde(default)]
+    pub avatar_url: Option<OwnedMxcUri>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Database {
     pub path: String,
     pub passphrase: String,
+    #[serde(default)]
+    pub persist_outgoing: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -103,6 +121,17 @@ pub struct JoinedMembersResponse {
     pub joined: HashMap<OwnedUserId, Profile>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct MembersResponse {
+    pub chunk: Vec<MemberStateEvent>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MemberStateEvent {
+    pub state_key: OwnedUserId,
+    pub content: RoomMemberEventContent,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct SendResponse {
     pub event_id: OwnedEventId,
@@ -128,6 +157,22 @@ pub struct JoinedRoomResponse {
     pub joined_rooms: Vec<OwnedRoomId>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct JoinRoomResponse {
+    pub room_id: OwnedRoomId,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResolveAliasResponse {
+    pub room_id: OwnedRoomId,
+    pub servers: Vec<OwnedServerName>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreateAliasRequest<'a> {
+    pub room_id: &'a RoomId,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct MessagesResponse {
     pub chunk: Vec<Raw<AnySyncTimelineEvent>>,
@@ -136,11 +181,80 @@ pub struct MessagesResponse {
     pub state: Option<Vec<Raw<AnySyncTimelineEvent>>>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct ContextResponse {
+    pub start: Option<String>,
+    pub end: Option<String>,
+    pub event: Option<Raw<AnySyncTimelineEvent>>,
+    #[serde(default)]
+    pub events_before: Vec<Raw<AnySyncTimelineEvent>>,
+    #[serde(default)]
+    pub events_after: Vec<Raw<AnySyncTimelineEvent>>,
+    #[serde(default)]
+    pub state: Vec<Raw<AnySyncStateEvent>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RelationsResponse {
+    pub chunk: Vec<Raw<AnySyncTimelineEvent>>,
+    pub next_batch: Option<String>,
+    pub prev_batch: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ThreadsResponse {
+    pub chunk: Vec<Raw<AnySyncTimelineEvent>>,
+    pub next_batch: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserDirectorySearchRequest<'a> {
+    pub search_term: &'a str,
+    pub limit: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UserDirectorySearchResponse {
+    pub results: Vec<DirectoryUser>,
+    pub limited: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DirectoryUser {
+    pub user_id: OwnedUserId,
+    pub display_name: Option<String>,
+    pub avatar_url: Option<OwnedMxcUri>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UploadResponse {
+    pub content_uri: OwnedMxcUri,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HierarchyResponse {
+    pub rooms: Vec<SpaceHierarchyRoomsChunk>,
+    pub next_batch: Option<String>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Devices {
     pub devices: Vec<Device>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct WhoamiResponse {
+    pub user_id: OwnedUserId,
+    pub device_id: Option<OwnedDeviceId>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VersionsResponse {
+    pub versions: Vec<String>,
+    #[serde(default)]
+    pub unstable_features: BTreeMap<String, bool>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Ping {
     pub transaction_id: String,
@@ -164,9 +278,9 @@ pub struct CreateDeviceRequest {
     pub display_name: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Profile {
-    pub avatar_url: Option<Url>,
+    pub avatar_url: Option<OwnedMxcUri>,
     #[serde(alias = "display_name")]
     pub displayname: Option<String>,
 }
@@ -1,28 +1,64 @@
 use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Weak};
 
-use matrix_sdk::ruma::exports::serde_json::json;
+use matrix_sdk::ruma::events::direct::DirectEventContent;
+use matrix_sdk::ruma::events::{
+    GlobalAccountDataEventContent,
+    MessageLikeEventContent,
+    RoomAccountDataEventContent,
+    StaticEventContent,
+};
+use matrix_sdk::ruma::exports::serde_json::{Value, json};
 use matrix_sdk::ruma::presence::PresenceState;
-use matrix_sdk::ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId};
+use matrix_sdk::ruma::{
+    DeviceId,
+    EventId,
+    MxcUri,
+    OwnedDeviceId,
+    OwnedEventId,
+    OwnedRoomId,
+    OwnedServerName,
+    OwnedUserId,
+    RoomId,
+    RoomOrAliasId,
+    TransactionId,
+    UserId,
+};
+use reqwest::{Method, StatusCode};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
 use tokio::sync::RwLock;
 
 use crate::appservice::device::{Device, DeviceInner};
+use crate::appservice::encryption::EncryptionInner;
 use crate::appservice::error::Error;
+use crate::appservice::event_handler::AccountDataContext;
 use crate::appservice::handler::ApplicationServiceReference;
-use crate::appservice::http_client::{discard_response, parse_response};
-use crate::appservice::types::{JoinedRoomResponse, Profile};
+use crate::appservice::http_client::{discard_response, encode_path_segment, parse_response};
+use crate::appservice::keyed_lock::{KeyedLock, KeyedLockGuard};
+use crate::appservice::outgoing_queue::OutgoingRequest;
+use crate::appservice::room::RoomKind;
+use crate::appservice::types::{
+    Devices,
+    JoinRoomResponse,
+    JoinedRoomResponse,
+    Profile,
+    UserDirectorySearchRequest,
+    UserDirectorySearchResponse,
+};
 use crate::appservice::{ApplicationServiceInner, Presence};
-use crate::{Empty, Result};
+use crate::{Empty, Result, SendResponse};
 
 #[derive(Debug)]
 pub struct UserInner {
     mxid: OwnedUserId,
-    device: RwLock<Option<Arc<DeviceInner>>>,
+    devices: RwLock<HashMap<OwnedDeviceId, Arc<DeviceInner>>>,
+    direct_rooms: RwLock<HashSet<OwnedRoomId>>,
 }
 
 impl UserInner {
     async fn new(mxid: OwnedUserId) -> Arc<Self> {
-        Arc::new(UserInner { mxid, device: RwLock::new(None) })
+        Arc::new(UserInner { mxid, devices: RwLock::new(HashMap::new()), direct_rooms: RwLock::new(HashSet::new()) })
     }
 
     fn upgrade(self: &Arc<Self>, appservice: Weak<ApplicationServiceInner>) -> Arc<User> {
@@ -57,35 +93,297 @@ impl User {
     }
 
     pub async fn get_device(self: &Arc<Self>) -> Option<Arc<Device>> {
-        let inner_option = self.inner.device.read().await;
-        match inner_option.as_ref() {
+        let devices = self.inner.devices.read().await;
+        let default_id = DeviceInner::default_id(self.id());
+
+        match devices.get(&default_id) {
             Some(inner) => Some(inner.upgrade(self)),
-            None => None,
+            None => devices.values().min_by(|a, b| a.id().cmp(b.id())).map(|inner| inner.upgrade(self)),
         }
     }
 
+    pub async fn get_device_by_id(self: &Arc<Self>, device_id: &DeviceId) -> Option<Arc<Device>> {
+        self.inner.devices.read().await.get(device_id).map(|inner| inner.upgrade(self))
+    }
+
+    pub async fn devices(self: &Arc<Self>) -> Vec<Arc<Device>> {
+        self.inner.devices.read().await.values().map(|inner| inner.upgrade(self)).collect()
+    }
+
     pub async fn create_device(self: &Arc<Self>, device_id: Option<&str>) -> Result<Arc<Device>> {
-        let inner = DeviceInner::new(self, device_id).await?;
-        let mut lock = self.inner.device.write().await;
-        *lock = Some(Arc::clone(&inner));
+        let device_id = match device_id {
+            Some(device_id) => OwnedDeviceId::from(device_id),
+            None => DeviceInner::default_id(self.id()),
+        };
+
+        let mut devices = self.inner.devices.write().await;
+        if let Some(inner) = devices.get(&device_id) {
+            tracing::debug!("Device {} of {} already exists", device_id, self.id());
+            return Ok(inner.upgrade(self));
+        }
+
+        let inner = DeviceInner::new(self, Some(device_id.as_str())).await?;
+        devices.insert(device_id, Arc::clone(&inner));
 
         Ok(inner.upgrade(self))
     }
 
     pub(crate) async fn populate_known_rooms(&self) -> Result<()> {
         let joined_rooms = self.get_joined_rooms().await?;
-        self.appservice()?.room_store().populate_known_rooms(&joined_rooms).await?;
+        self.appservice()?.room_store().populate_known_rooms(self, &joined_rooms).await?;
+        self.load_direct_rooms().await?;
 
         Ok(())
     }
 
+    pub async fn is_direct(&self, room_id: &RoomId) -> bool {
+        self.inner.direct_rooms.read().await.contains(room_id)
+    }
+
+    pub async fn direct_rooms(&self) -> HashSet<OwnedRoomId> {
+        self.inner.direct_rooms.read().await.clone()
+    }
+
+    pub async fn get_account_data<C>(&self) -> Result<Option<C>>
+    where
+        C: GlobalAccountDataEventContent + StaticEventContent + DeserializeOwned,
+    {
+        let url = format!("/_matrix/client/v3/user/{}/account_data/{}", self.id(), C::TYPE);
+        self.fetch_account_data(&url).await
+    }
+
+    pub async fn set_account_data<C>(&self, content: &C) -> Result<()>
+    where
+        C: GlobalAccountDataEventContent + StaticEventContent + Serialize,
+    {
+        let url = format!("/_matrix/client/v3/user/{}/account_data/{}", self.id(), C::TYPE);
+        self.put_account_data(&url, None, C::TYPE, content).await
+    }
+
+    pub async fn get_room_account_data<C>(&self, room_id: &RoomId) -> Result<Option<C>>
+    where
+        C: RoomAccountDataEventContent + StaticEventContent + DeserializeOwned,
+    {
+        let url = format!("/_matrix/client/v3/user/{}/rooms/{}/account_data/{}", self.id(), room_id, C::TYPE);
+        self.fetch_account_data(&url).await
+    }
+
+    pub async fn set_room_account_data<C>(&self, room_id: &RoomId, content: &C) -> Result<()>
+    where
+        C: RoomAccountDataEventContent + StaticEventContent + Serialize,
+    {
+        let url = format!("/_matrix/client/v3/user/{}/rooms/{}/account_data/{}", self.id(), room_id, C::TYPE);
+        self.put_account_data(&url, Some(room_id), C::TYPE, content).await
+    }
+
+    pub(crate) async fn copy_room_account_data(
+        &self,
+        predecessor: &RoomId,
+        successor: &RoomId,
+        event_type: &str,
+    ) -> Result<()> {
+        let url = format!("/_matrix/client/v3/user/{}/rooms/{}/account_data/{}", self.id(), predecessor, event_type);
+        let Some(content) = self.fetch_account_data::<Value>(&url).await? else {
+            return Ok(());
+        };
+
+        let url = format!("/_matrix/client/v3/user/{}/rooms/{}/account_data/{}", self.id(), successor, event_type);
+        self.put_account_data(&url, Some(successor), event_type, &content).await
+    }
+
+    async fn fetch_account_data<C: DeserializeOwned>(&self, url: &str) -> Result<Option<C>> {
+        let response = self.client()?.get(url).query(&[("user_id", self.id())]).send().await?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            _ => Ok(Some(parse_response(response).await?)),
+        }
+    }
+
+    async fn put_account_data(
+        &self,
+        url: &str,
+        room_id: Option<&RoomId>,
+        event_type: &str,
+        content: &impl Serialize,
+    ) -> Result<()> {
+        tracing::debug!("Updating account data {} of {}", event_type, self.id());
+        let content = serde_json::to_value(content)?;
+        let response = self.client()?.put(url).query(&[("user_id", self.id())]).json(&content).send().await?;
+        discard_response(response).await?;
+
+        let context = AccountDataContext { user_id: self.id().to_owned(), room_id: room_id.map(RoomId::to_owned) };
+        self.appservice()?.handler_store().dispatch_account_data(event_type, content, context).await;
+
+        Ok(())
+    }
+
+    pub async fn get_direct_account_data(&self) -> Result<DirectEventContent> {
+        Ok(self.get_account_data::<DirectEventContent>().await?.unwrap_or_default())
+    }
+
+    pub(crate) async fn load_direct_rooms(&self) -> Result<()> {
+        let content = self.get_direct_account_data().await?;
+        let rooms = content.0.into_values().flatten().collect::<HashSet<_>>();
+
+        tracing::debug!("User {} has {} direct room(s)", self.id(), rooms.len());
+        *self.inner.direct_rooms.write().await = rooms;
+
+        Ok(())
+    }
+
+    pub(crate) async fn mark_direct(&self, room_id: &RoomId, target: &UserId) -> Result<()> {
+        self.inner.direct_rooms.write().await.insert(room_id.to_owned());
+
+        let mut content = self.get_direct_account_data().await?;
+        let rooms = content.entry(target.into()).or_default();
+        if rooms.iter().any(|known| known == room_id) {
+            return Ok(());
+        }
+        rooms.push(room_id.to_owned());
+
+        tracing::info!("Marking room {} as direct chat between {} and {}", room_id, self.id(), target);
+        self.set_account_data(&content).await
+    }
+
+    pub(crate) async fn replace_direct_room(&self, predecessor: &RoomId, successor: &RoomId) -> Result<()> {
+        if !self.is_direct(predecessor).await {
+            return Ok(());
+        }
+        self.inner.direct_rooms.write().await.insert(successor.to_owned());
+
+        let mut content = self.get_direct_account_data().await?;
+        for rooms in content.values_mut() {
+            if rooms.iter().any(|known| known == predecessor) && !rooms.iter().any(|known| known == successor) {
+                rooms.push(successor.to_owned());
+            }
+        }
+
+        tracing::info!("Moving direct chat of {} from room {} to {}", self.id(), predecessor, successor);
+        self.set_account_data(&content).await
+    }
+
     pub(crate) async fn update_tracked_users(self: &Arc<Self>, users: &HashSet<OwnedUserId>) -> Result<()> {
-        match self.get_device().await {
-            Some(device) => device.encryption().update_tracked_users(users).await,
-            None => Ok(()),
+        for device in self.devices().await {
+            device.encryption().update_tracked_users(users).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn send_event<'a, C>(
+        self: &Arc<Self>,
+        room: impl Into<&'a RoomOrAliasId>,
+        content: C,
+    ) -> Result<OwnedEventId>
+    where
+        C: MessageLikeEventContent,
+    {
+        let appservice = self.appservice()?;
+        let room_id = appservice.resolve_room_id(room.into()).await?;
+        let room = appservice.get_room(&*room_id).await?.ok_or(Error::RoomNotFound(room_id.clone()))?;
+
+        match room.kind().as_ref() {
+            RoomKind::Encrypted(_) => match self.get_device().await {
+                Some(device) => device.send(&*room_id, content).await,
+                None => Err(Error::NoDevice(self.id().to_owned())),
+            },
+            RoomKind::Unencrypted(_) => {
+                let event_type = content.event_type().to_string();
+                self.put_room_event(&room_id, &event_type, &serde_json::to_value(content)?).await
+            }
         }
     }
 
+    pub(crate) async fn put_room_event(
+        &self,
+        room_id: &RoomId,
+        event_type: &str,
+        payload: &Value,
+    ) -> Result<OwnedEventId> {
+        let path = format!(
+            "/_matrix/client/v3/rooms/{}/send/{}/{}",
+            encode_path_segment(room_id.as_str()),
+            encode_path_segment(event_type),
+            TransactionId::new()
+        );
+
+        let json: SendResponse =
+            serde_json::from_value(self.queue(room_id, Method::PUT, path, payload.clone()).await?)?;
+        Ok(json.event_id)
+    }
+
+    pub async fn redact<'a>(
+        &self,
+        room: impl Into<&'a RoomOrAliasId>,
+        event_id: &EventId,
+        reason: Option<&str>,
+    ) -> Result<OwnedEventId> {
+        let room_id = self.appservice()?.resolve_room_id(room.into()).await?;
+        tracing::info!("Redacting event {} in room {} as {}", event_id, room_id, self.id());
+        let path = format!(
+            "/_matrix/client/v3/rooms/{}/redact/{}/{}",
+            encode_path_segment(room_id.as_str()),
+            encode_path_segment(event_id.as_str()),
+            TransactionId::new()
+        );
+
+        let mut body = json!({});
+        if let Some(reason) = reason {
+            body["reason"] = reason.into();
+        }
+
+        let json: SendResponse = serde_json::from_value(self.queue(&room_id, Method::PUT, path, body).await?)?;
+        Ok(json.event_id)
+    }
+
+    pub async fn kick<'a>(
+        &self,
+        room: impl Into<&'a RoomOrAliasId>,
+        user_id: &UserId,
+        reason: Option<&str>,
+    ) -> Result<()> {
+        self.moderate(room.into(), "kick", user_id, reason).await
+    }
+
+    pub async fn ban<'a>(
+        &self,
+        room: impl Into<&'a RoomOrAliasId>,
+        user_id: &UserId,
+        reason: Option<&str>,
+    ) -> Result<()> {
+        self.moderate(room.into(), "ban", user_id, reason).await
+    }
+
+    pub async fn unban<'a>(
+        &self,
+        room: impl Into<&'a RoomOrAliasId>,
+        user_id: &UserId,
+        reason: Option<&str>,
+    ) -> Result<()> {
+        self.moderate(room.into(), "unban", user_id, reason).await
+    }
+
+    async fn moderate(&self, room: &RoomOrAliasId, action: &str, user_id: &UserId, reason: Option<&str>) -> Result<()> {
+        let room_id = self.appservice()?.resolve_room_id(room).await?;
+        tracing::info!("Sending {} of {} in room {} as {}", action, user_id, room_id, self.id());
+        let path = format!("/_matrix/client/v3/rooms/{}/{}", encode_path_segment(room_id.as_str()), action);
+
+        let mut body = json!({ "user_id": user_id });
+        if let Some(reason) = reason {
+            body["reason"] = reason.into();
+        }
+
+        self.queue(&room_id, Method::POST, path, body).await?;
+        Ok(())
+    }
+
+    pub(crate) async fn queue(&self, room_id: &RoomId, method: Method, path: String, body: Value) -> Result<Value> {
+        let request =
+            OutgoingRequest { room_id: room_id.to_owned(), user_id: self.id().to_owned(), method, path, body };
+        self.appservice()?.outgoing_queue().send(request).await
+    }
+
     pub async fn get_joined_rooms(&self) -> Result<Vec<OwnedRoomId>> {
         tracing::info!("Retrieving room membership of {}", &self.id());
         let url = "/_matrix/client/v3/joined_rooms";
@@ -108,17 +406,71 @@ impl User {
         parse_response(response).await
     }
 
-    pub async fn join_room(&self, room_id: &RoomId) -> Result<()> {
-        let url = format!("/_matrix/client/v3/rooms/{}/join", room_id.as_str());
-        let response = self.client()?.post(&url).query(&[("user_id", self.id().to_owned())]).send().await?;
+    pub async fn join_room<'a>(&self, room: impl Into<&'a RoomOrAliasId>) -> Result<OwnedRoomId> {
+        self.join_room_via(room, &[]).await
+    }
+
+    pub async fn join_room_via<'a>(
+        &self,
+        room: impl Into<&'a RoomOrAliasId>,
+        servers: &[OwnedServerName],
+    ) -> Result<OwnedRoomId> {
+        let room = room.into();
+        tracing::info!("Joining room {} as {}", room, self.id());
+        let url = format!("/_matrix/client/v3/join/{}", encode_path_segment(room.as_str()));
+
+        let mut params = vec![("user_id", self.id().to_string())];
+        params.extend(servers.iter().map(|server| ("server_name", server.to_string())));
+        params.extend(servers.iter().map(|server| ("via", server.to_string())));
 
-        discard_response(response).await
+        let response = self.client()?.post(&url).json(&json!({})).query(&params).send().await?;
+
+        let json: JoinRoomResponse = parse_response(response).await?;
+        Ok(json.room_id)
     }
 
     pub async fn get_devices(&self) -> Result<Vec<matrix_sdk::ruma::api::client::device::Device>> {
         tracing::info!("Fetching devices of user {}", self.id());
         let url = "/_matrix/client/v3/devices";
-        let response = self.client()?.get(url).send().await?;
+        let response = self.client()?.get(url).query(&[("user_id", self.id())]).send().await?;
+        let json: Devices = parse_response(response).await?;
+
+        Ok(json.devices)
+    }
+
+    pub async fn remove_orphaned_devices(self: &Arc<Self>) -> Result<Vec<OwnedDeviceId>> {
+        let database_path = self.appservice()?.config().database.path.clone();
+        let known_devices = self.inner.devices.read().await.keys().cloned().collect::<HashSet<_>>();
+
+        let mut removed = Vec::new();
+        for device in self.get_devices().await? {
+            // Devices with a local crypto store may just not have been loaded yet, so only storeless ones are orphans.
+            let has_store =
+                tokio::fs::try_exists(EncryptionInner::store_path(&database_path, &device.device_id)).await?;
+            if known_devices.contains(&device.device_id) || has_store {
+                continue;
+            }
+
+            tracing::info!("Deleting orphaned device {} of user {}", device.device_id, self.id());
+            let url = format!("/_matrix/client/v3/devices/{}", encode_path_segment(device.device_id.as_str()));
+            let response = self.client()?.delete(&url).query(&[("user_id", self.id())]).send().await?;
+            discard_response(response).await?;
+
+            removed.push(device.device_id);
+        }
+
+        Ok(removed)
+    }
+
+    pub(crate) async fn remove_device(&self, device_id: &DeviceId) {
+        self.inner.devices.write().await.remove(device_id);
+    }
+
+    pub async fn search_directory(&self, term: &str, limit: usize) -> Result<UserDirectorySearchResponse> {
+        tracing::debug!("Searching user directory for {:?} as {}", term, self.id());
+        let url = "/_matrix/client/v3/user_directory/search";
+        let body = UserDirectorySearchRequest { search_term: term, limit };
+        let response = self.client()?.post(url).query(&[("user_id", self.id())]).json(&body).send().await?;
 
         parse_response(response).await
     }
@@ -134,7 +486,16 @@ impl User {
         tracing::info!("Updating display name of {} to {}", self.id(), displayname);
         let url = format!("/_matrix/client/v3/profile/{}/displayname", self.id());
         let body = json!({"displayname": displayname});
-        let response = self.client()?.put(&url).json(&body).send().await?;
+        let response = self.client()?.put(&url).query(&[("user_id", self.id())]).json(&body).send().await?;
+
+        parse_response(response).await
+    }
+
+    pub async fn set_avatar_url(&self, avatar_url: &MxcUri) -> Result<Empty> {
+        tracing::info!("Updating avatar of {} to {}", self.id(), avatar_url);
+        let url = format!("/_matrix/client/v3/profile/{}/avatar_url", self.id());
+        let body = json!({"avatar_url": avatar_url});
+        let response = self.client()?.put(&url).query(&[("user_id", self.id())]).json(&body).send().await?;
 
         parse_response(response).await
     }
@@ -152,6 +513,7 @@ impl User {
 pub struct UserStore {
     appservice: Weak<ApplicationServiceInner>,
     users: RwLock<HashMap<OwnedUserId, Arc<UserInner>>>,
+    puppets: KeyedLock<OwnedUserId>,
 }
 
 impl ApplicationServiceReference for UserStore {
@@ -165,7 +527,7 @@ impl ApplicationServiceReference for UserStore {
 
 impl UserStore {
     pub fn new(appservice: Weak<ApplicationServiceInner>) -> Self {
-        Self { appservice, users: RwLock::new(HashMap::new()) }
+        Self { appservice, users: RwLock::new(HashMap::new()), puppets: KeyedLock::new() }
     }
 
     pub async fn insert(&self, user: Arc<UserInner>) {
@@ -184,4 +546,8 @@ impl UserStore {
     pub async fn keys(&self) -> HashSet<OwnedUserId> {
         self.users.read().await.keys().cloned().collect()
     }
+
+    pub(crate) async fn lock_puppet(&self, mxid: &OwnedUserId) -> KeyedLockGuard<OwnedUserId> {
+        self.puppets.lock(mxid).await
+    }
 }
@@ -6,13 +6,26 @@ pub mod exports {
 
 pub use appservice::types::*;
 pub use appservice::{
+    AccountDataContext,
     ApplicationService,
     ApplicationServiceBuilder,
     Device,
     Direction,
     Error,
     EventContext,
+    Feature,
+    HistoryEvent,
+    MemberChange,
+    MemberChangeKind,
+    MessageBuilder,
+    MessagesPage,
+    MessagesRequest,
     Result,
     Room,
+    RoomEventContext,
+    RoomMember,
+    RoomUpgrade,
+    ServerCapabilities,
+    UnableToDecryptEvent,
     User,
 };